@@ -0,0 +1,123 @@
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use hawk_pack::examples::lazy_memory_store::LazyMemoryStore;
+use hawk_pack::graph_store::{LinksEncoding, TestGraphPg};
+use hawk_pack::hnsw_db::FurthestQueue;
+use hawk_pack::{GraphStore, VectorStore};
+
+/// Compare the latency of `set_links` and `get_links` with 32 links in each encoding.
+fn links_encoding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("graph-pg-links");
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    for (name, encoding) in [
+        ("json", LinksEncoding::Json),
+        ("bincode", LinksEncoding::Bincode),
+        ("edges", LinksEncoding::Edges),
+    ] {
+        let (graph, base, links) = runtime.block_on(async {
+            let graph = TestGraphPg::<LazyMemoryStore>::new_with_encoding(encoding)
+                .await
+                .unwrap();
+
+            let mut vector_store = LazyMemoryStore::new();
+            let query = vector_store.prepare_query_u64(0);
+            let base = vector_store.insert(&query).await;
+
+            let mut links = FurthestQueue::new();
+            for raw_query in 1..33 {
+                let q = vector_store.prepare_query_u64(raw_query);
+                let v = vector_store.insert(&q).await;
+                let d = vector_store.eval_distance(&query, &v).await;
+                links.insert(&vector_store, v, d).await;
+            }
+            (graph, base, links)
+        });
+        let mut store = graph.owned();
+
+        group.bench_function(BenchmarkId::new("set_links", name), |b| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    store.set_links(base, links.clone(), 0).await.unwrap();
+                });
+            })
+        });
+
+        group.bench_function(BenchmarkId::new("get_links", name), |b| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    store.get_links(&base, 0).await.unwrap();
+                });
+            })
+        });
+
+        runtime.block_on(graph.cleanup()).unwrap();
+    }
+}
+
+/// Compare the latency of `set_links` when one link of 32 changes, as in an insertion, in each encoding.
+///
+/// The JSON encoding rewrites the whole list, and the edges encoding writes one row and deletes another, after reading
+/// the list. Measured against a local database: 148µs with JSON, 318µs with edges, due to the additional round trips.
+fn single_edge_update(c: &mut Criterion) {
+    let mut group = c.benchmark_group("graph-pg-single-edge");
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    for (name, encoding) in [
+        ("json", LinksEncoding::Json),
+        ("edges", LinksEncoding::Edges),
+    ] {
+        let (graph, base, versions) = runtime.block_on(async {
+            let graph = TestGraphPg::<LazyMemoryStore>::new_with_encoding(encoding)
+                .await
+                .unwrap();
+
+            let mut vector_store = LazyMemoryStore::new();
+            let query = vector_store.prepare_query_u64(0);
+            let base = vector_store.insert(&query).await;
+
+            let mut links = FurthestQueue::new();
+            for raw_query in 1..33 {
+                let q = vector_store.prepare_query_u64(raw_query);
+                let v = vector_store.insert(&q).await;
+                let d = vector_store.eval_distance(&query, &v).await;
+                links.insert(&vector_store, v, d).await;
+            }
+
+            // The same list with a new nearest link, and the furthest one trimmed.
+            let mut updated = links.clone();
+            let q = vector_store.prepare_query_u64(1 << 8);
+            let v = vector_store.insert(&q).await;
+            let d = vector_store.eval_distance(&query, &v).await;
+            updated.insert(&vector_store, v, d).await;
+            updated.trim_to_k_nearest(links.len());
+
+            (graph, base, [links, updated])
+        });
+        let mut store = graph.owned();
+        runtime
+            .block_on(store.set_links(base, versions[0].clone(), 0))
+            .unwrap();
+
+        // Alternate between both versions, so each write changes one link.
+        let mut i = 0;
+        group.bench_function(BenchmarkId::new("set_links", name), |b| {
+            b.iter(|| {
+                i += 1;
+                runtime.block_on(async {
+                    store
+                        .set_links(base, versions[i % 2].clone(), 0)
+                        .await
+                        .unwrap();
+                });
+            })
+        });
+
+        runtime.block_on(graph.cleanup()).unwrap();
+    }
+}
+
+criterion_group!(graph_pg, links_encoding, single_edge_update);
+criterion_main!(graph_pg);
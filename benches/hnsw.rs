@@ -3,12 +3,18 @@ use criterion::criterion_group;
 use criterion::criterion_main;
 use criterion::BenchmarkId;
 use criterion::Criterion;
-use hawk_pack::examples::lazy_memory_store::LazyMemoryStore;
+use hawk_pack::error::Result;
+use hawk_pack::examples::lazy_memory_store::{LazyMemoryStore, PointId};
 use hawk_pack::graph_store::graph_mem::GraphMem;
-use hawk_pack::hnsw_db::HawkSearcher;
+use hawk_pack::graph_store::{EntryPoint, GraphStore, GraphVec};
+use hawk_pack::hnsw_db::{FurthestQueueV, HawkSearcher, Params};
 use hawk_pack::linear_db::LinearDb;
 use hawk_pack::VectorStore;
+use rand::rngs::SmallRng;
 use rand::SeedableRng;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::time::Duration;
 
 fn hnsw_db(c: &mut Criterion) {
     let mut group = c.benchmark_group("hnsw");
@@ -19,7 +25,7 @@ fn hnsw_db(c: &mut Criterion) {
         let mut initial_db = HawkSearcher::new(vector_store, graph_store, &mut rng);
 
         let queries = (0..database_size)
-            .map(|raw_query| initial_db.vector_store.prepare_query(raw_query))
+            .map(|raw_query| initial_db.vector_store.prepare_query_u64(raw_query))
             .collect::<Vec<_>>();
 
         let runtime = tokio::runtime::Runtime::new().unwrap();
@@ -27,13 +33,14 @@ fn hnsw_db(c: &mut Criterion) {
 
         let full_db = runtime.block_on(async move {
             for query in queries.iter() {
-                let neighbors = initial_db.search_to_insert(query).await;
+                let neighbors = initial_db.search_to_insert(query).await.unwrap();
                 assert!(!initial_db.is_match(&neighbors).await);
                 // Insert the new vector into the store.
                 let inserted = initial_db.vector_store.insert(query).await;
                 initial_db
                     .insert_from_search_results(inserted, neighbors)
-                    .await;
+                    .await
+                    .unwrap();
             }
             initial_db
         });
@@ -43,10 +50,13 @@ fn hnsw_db(c: &mut Criterion) {
                 |my_db| {
                     runtime.block_on(async move {
                         let raw_query = database_size;
-                        let query = my_db.vector_store.prepare_query(raw_query);
-                        let neighbors = my_db.search_to_insert(&query).await;
+                        let query = my_db.vector_store.prepare_query_u64(raw_query);
+                        let neighbors = my_db.search_to_insert(&query).await.unwrap();
                         let inserted = my_db.vector_store.insert(&query).await;
-                        my_db.insert_from_search_results(inserted, neighbors).await;
+                        my_db
+                            .insert_from_search_results(inserted, neighbors)
+                            .await
+                            .unwrap();
                     });
                 },
                 criterion::BatchSize::SmallInput,
@@ -55,6 +65,418 @@ fn hnsw_db(c: &mut Criterion) {
     }
 }
 
+/// Compare searches in a flat graph (layer 0 only) with searches in a multi-layer graph.
+fn hnsw_flat(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hnsw-flat");
+    let database_size = 1000;
+    for (name, m_l) in [("multi-layer", Params::default().m_L), ("flat", 0.0)] {
+        let vector_store = LazyMemoryStore::new();
+        let graph_store = GraphMem::new();
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let params = Params {
+            m_L: m_l,
+            ..Params::default()
+        };
+        let mut initial_db =
+            HawkSearcher::new_with_params(vector_store, graph_store, params, &mut rng);
+
+        let queries = (0..database_size)
+            .map(|raw_query| initial_db.vector_store.prepare_query_u64(raw_query))
+            .collect::<Vec<_>>();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let mut full_db = runtime.block_on(async move {
+            for query in queries.iter() {
+                let neighbors = initial_db.search_to_insert(query).await.unwrap();
+                let inserted = initial_db.vector_store.insert(query).await;
+                initial_db
+                    .insert_from_search_results(inserted, neighbors)
+                    .await
+                    .unwrap();
+            }
+            initial_db
+        });
+        let query = full_db.vector_store.prepare_query_u64(database_size);
+
+        group.bench_function(BenchmarkId::new("hnsw-search", name), |b| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    full_db.search_to_insert(&query).await.unwrap();
+                });
+            })
+        });
+    }
+}
+
+/// Compare searches in a GraphMem (hash maps) with searches in a GraphVec (dense vectors).
+fn hnsw_graph_vec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hnsw-graph-vec");
+    let database_size = 100000;
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut vector_store = LazyMemoryStore::new();
+    let queries = (0..database_size)
+        .map(|raw_query| vector_store.prepare_query_u64(raw_query))
+        .collect::<Vec<_>>();
+    let query = vector_store.prepare_query_u64(database_size);
+
+    let mem_db = runtime.block_on(build(vector_store.clone(), GraphMem::new(), &queries));
+    let vec_db = runtime.block_on(build(vector_store, GraphVec::new(), &queries));
+
+    group.bench_function(BenchmarkId::new("hnsw-search", "GraphMem"), |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                mem_db.search_to_insert(&query).await.unwrap();
+            });
+        })
+    });
+    group.bench_function(BenchmarkId::new("hnsw-search", "GraphVec"), |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                vec_db.search_to_insert(&query).await.unwrap();
+            });
+        })
+    });
+}
+
+async fn build<G: GraphStore<LazyMemoryStore>>(
+    vector_store: LazyMemoryStore,
+    graph_store: G,
+    queries: &[<LazyMemoryStore as VectorStore>::QueryRef],
+) -> HawkSearcher<LazyMemoryStore, G> {
+    let mut db = HawkSearcher::from_seed(vector_store, graph_store, 0);
+    for query in queries.iter() {
+        let neighbors = db.search_to_insert(query).await.unwrap();
+        let inserted = db.vector_store.insert(query).await;
+        db.insert_from_search_results(inserted, neighbors)
+            .await
+            .unwrap();
+    }
+    db
+}
+
+/// Compare building a graph with one insertion at a time, and with concurrent searches.
+fn hnsw_build_from_stream(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hnsw-build-from-stream");
+    group.sample_size(10);
+    let database_size = 100000;
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut vector_store = LazyMemoryStore::new();
+    let queries = (0..database_size)
+        .map(|raw_query| vector_store.prepare_query_u64(raw_query))
+        .collect::<Vec<_>>();
+
+    group.bench_function(BenchmarkId::new("hnsw-build", "serial"), |b| {
+        b.iter(|| {
+            runtime.block_on(build(vector_store.clone(), GraphMem::new(), &queries));
+        })
+    });
+    for concurrency in [4, 16] {
+        group.bench_function(BenchmarkId::new("hnsw-build", concurrency), |b| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let mut db = HawkSearcher::from_seed(vector_store.clone(), GraphMem::new(), 0);
+                    db.build_from_stream(futures::stream::iter(queries.clone()), concurrency)
+                        .await
+                        .unwrap();
+                });
+            })
+        });
+    }
+}
+
+/// A graph store that waits a fixed latency on each call, like a round trip to a database.
+#[derive(Clone, Debug)]
+struct Delayed<G> {
+    inner: G,
+    latency: Duration,
+}
+
+impl<G: GraphStore<LazyMemoryStore>> GraphStore<LazyMemoryStore> for Delayed<G> {
+    async fn get_entry_point(&self) -> Result<Option<EntryPoint<PointId>>> {
+        tokio::time::sleep(self.latency).await;
+        self.inner.get_entry_point().await
+    }
+
+    async fn set_entry_point(&mut self, entry_point: EntryPoint<PointId>) -> Result<()> {
+        tokio::time::sleep(self.latency).await;
+        self.inner.set_entry_point(entry_point).await
+    }
+
+    async fn get_links(
+        &self,
+        base: &PointId,
+        lc: usize,
+    ) -> Result<FurthestQueueV<LazyMemoryStore>> {
+        tokio::time::sleep(self.latency).await;
+        self.inner.get_links(base, lc).await
+    }
+
+    async fn get_links_batch(
+        &self,
+        bases: &[PointId],
+        lc: usize,
+    ) -> Result<Vec<FurthestQueueV<LazyMemoryStore>>> {
+        tokio::time::sleep(self.latency).await;
+        self.inner.get_links_batch(bases, lc).await
+    }
+
+    async fn set_links(
+        &mut self,
+        base: PointId,
+        links: FurthestQueueV<LazyMemoryStore>,
+        lc: usize,
+    ) -> Result<()> {
+        tokio::time::sleep(self.latency).await;
+        self.inner.set_links(base, links, lc).await
+    }
+
+    async fn set_links_batch(
+        &mut self,
+        links: Vec<(PointId, FurthestQueueV<LazyMemoryStore>)>,
+        lc: usize,
+    ) -> Result<()> {
+        tokio::time::sleep(self.latency).await;
+        self.inner.set_links_batch(links, lc).await
+    }
+}
+
+/// Compare building a graph one insertion at a time, and with concurrent searches, on a graph store that waits 1 ms
+/// on each call, where `build_from_stream` is expected to help.
+fn hnsw_build_from_stream_latency(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hnsw-build-from-stream-latency");
+    group.sample_size(10);
+    let database_size = 1000;
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut vector_store = LazyMemoryStore::new();
+    let queries = (0..database_size)
+        .map(|raw_query| vector_store.prepare_query_u64(raw_query))
+        .collect::<Vec<_>>();
+    let graph_store = Delayed {
+        inner: GraphMem::new(),
+        latency: Duration::from_millis(1),
+    };
+
+    group.bench_function(BenchmarkId::new("hnsw-build", "serial"), |b| {
+        b.iter(|| {
+            runtime.block_on(build(vector_store.clone(), graph_store.clone(), &queries));
+        })
+    });
+    for concurrency in [4, 16] {
+        group.bench_function(BenchmarkId::new("hnsw-build", concurrency), |b| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let mut db =
+                        HawkSearcher::from_seed(vector_store.clone(), graph_store.clone(), 0);
+                    db.build_from_stream(futures::stream::iter(queries.clone()), concurrency)
+                        .await
+                        .unwrap();
+                });
+            })
+        });
+    }
+}
+
+/// Compare building a graph with the back-links from all the neighbors or only a few, see `Params::bidirectional`.
+fn hnsw_bidirectional(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hnsw-bidirectional");
+    group.sample_size(10);
+    let database_size = 100000;
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut vector_store = LazyMemoryStore::new();
+    let queries = (0..database_size)
+        .map(|raw_query| vector_store.prepare_query_u64(raw_query))
+        .collect::<Vec<_>>();
+
+    for bidirectional in [true, false] {
+        let params = Params {
+            bidirectional,
+            ..Params::default()
+        };
+        group.bench_function(BenchmarkId::new("hnsw-build", bidirectional), |b| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let mut rng = AesRng::seed_from_u64(0_u64);
+                    let mut db = HawkSearcher::new_with_params(
+                        vector_store.clone(),
+                        GraphMem::new(),
+                        params.clone(),
+                        &mut rng,
+                    );
+                    for query in queries.iter() {
+                        let neighbors = db.search_to_insert(query).await.unwrap();
+                        let inserted = db.vector_store.insert(query).await;
+                        db.insert_from_search_results(inserted, neighbors)
+                            .await
+                            .unwrap();
+                    }
+                });
+            })
+        });
+    }
+}
+
+/// Compare building a graph with the default `AesRng`, and with a faster non-cryptographic RNG.
+fn hnsw_rng(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hnsw-rng");
+    group.sample_size(10);
+    let database_size = 10000;
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut vector_store = LazyMemoryStore::new();
+    let queries = (0..database_size)
+        .map(|raw_query| vector_store.prepare_query_u64(raw_query))
+        .collect::<Vec<_>>();
+
+    group.bench_function(BenchmarkId::new("hnsw-build", "AesRng"), |b| {
+        b.iter(|| {
+            runtime.block_on(build(vector_store.clone(), GraphMem::new(), &queries));
+        })
+    });
+    group.bench_function(BenchmarkId::new("hnsw-build", "SmallRng"), |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let mut db = HawkSearcher::new_with_rng(
+                    vector_store.clone(),
+                    GraphMem::new(),
+                    Params::default(),
+                    SmallRng::seed_from_u64(0),
+                );
+                for query in queries.iter() {
+                    let neighbors = db.search_to_insert(query).await.unwrap();
+                    let inserted = db.vector_store.insert(query).await;
+                    db.insert_from_search_results(inserted, neighbors)
+                        .await
+                        .unwrap();
+                }
+            });
+        })
+    });
+}
+
+/// Measure the cost of shifting the tail of `FurthestQueue` on insertion, against a `VecDeque` that shifts the shorter
+/// side, with the insertions of a search with ef=32, and the construction time that they are part of.
+fn hnsw_furthest_queue(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hnsw-furthest-queue");
+    group.sample_size(10);
+    let database_size = 100000;
+    let ef = 32;
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut vector_store = LazyMemoryStore::new();
+    let queries = (0..database_size)
+        .map(|raw_query| vector_store.prepare_query_u64(raw_query))
+        .collect::<Vec<_>>();
+
+    group.bench_function(BenchmarkId::new("hnsw-build", database_size), |b| {
+        b.iter(|| {
+            runtime.block_on(build(vector_store.clone(), GraphMem::new(), &queries));
+        })
+    });
+
+    // The distances from one query to all the vectors, in a pseudo-random order.
+    let query = vector_store.prepare_query_u64(database_size);
+    let distances = (0..database_size as usize)
+        .map(|i| queries[i * 7919 % database_size as usize])
+        .map(|vector| {
+            let distance = runtime.block_on(vector_store.eval_distance(&query, &vector));
+            (vector, distance)
+        })
+        .collect::<Vec<_>>();
+
+    group.bench_function(BenchmarkId::new("insert", "Vec"), |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let mut queue = FurthestQueueV::<LazyMemoryStore>::new();
+                for (vector, distance) in distances.iter().cloned() {
+                    if queue.len() == ef {
+                        let (_, furthest) = queue.get_furthest().unwrap();
+                        if !vector_store.less_than(&distance, furthest).await {
+                            continue;
+                        }
+                        queue.pop_furthest();
+                    }
+                    queue.insert(&vector_store, vector, distance).await;
+                }
+                criterion::black_box(queue.len());
+            });
+        })
+    });
+    group.bench_function(BenchmarkId::new("insert", "VecDeque"), |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let mut queue = VecDeque::new();
+                for (vector, distance) in distances.iter().cloned() {
+                    if queue.len() == ef {
+                        let (_, furthest) = queue.back().unwrap();
+                        if !vector_store.less_than(&distance, furthest).await {
+                            continue;
+                        }
+                        queue.pop_back();
+                    }
+                    // The same search as `FurthestQueue::insert`.
+                    let queue_distances = queue.iter().map(|(_, d)| *d).collect::<Vec<_>>();
+                    let mut index = vector_store
+                        .search_sorted(&queue_distances, &distance)
+                        .await;
+                    while let Some((v, d)) = queue.get(index) {
+                        if vector_store.tie_break(v, &vector) != Ordering::Less
+                            || vector_store.less_than(&distance, d).await
+                        {
+                            break;
+                        }
+                        index += 1;
+                    }
+                    queue.insert(index, (vector, distance));
+                }
+                criterion::black_box(queue.len());
+            });
+        })
+    });
+}
+
+/// Compare reading links with a copy (`get_links`) and without (`with_links`).
+fn graph_mem_links(c: &mut Criterion) {
+    let mut group = c.benchmark_group("graph-mem-links");
+    let database_size = 1000;
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut vector_store = LazyMemoryStore::new();
+    let queries = (0..database_size)
+        .map(|raw_query| vector_store.prepare_query_u64(raw_query))
+        .collect::<Vec<_>>();
+    let db = runtime.block_on(build(vector_store, GraphMem::new(), &queries));
+
+    group.bench_function("get_links", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                for query in queries.iter() {
+                    let links = db.graph_store.get_links(query, 0).await.unwrap();
+                    criterion::black_box(links.len());
+                }
+            });
+        })
+    });
+    group.bench_function("with_links", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                for query in queries.iter() {
+                    let len = db
+                        .graph_store
+                        .with_links(query, 0, |links| links.len())
+                        .await
+                        .unwrap();
+                    criterion::black_box(len);
+                }
+            });
+        })
+    });
+}
+
 fn linear(c: &mut Criterion) {
     let mut group = c.benchmark_group("linear");
     for database_size in [1000, 10000, 100000] {
@@ -62,7 +484,7 @@ fn linear(c: &mut Criterion) {
         let mut initial_db = LinearDb::new(vector_store);
 
         let queries = (0..database_size)
-            .map(|raw_query| initial_db.store.prepare_query(raw_query))
+            .map(|raw_query| initial_db.store.prepare_query_u64(raw_query))
             .collect::<Vec<_>>();
 
         let runtime = tokio::runtime::Runtime::new().unwrap();
@@ -80,7 +502,7 @@ fn linear(c: &mut Criterion) {
                 |my_db| {
                     runtime.block_on(async move {
                         let raw_query = database_size;
-                        let query = my_db.store.prepare_query(raw_query);
+                        let query = my_db.store.prepare_query_u64(raw_query);
                         let _inserted = my_db.store.insert(&query).await;
                     });
                 },
@@ -90,5 +512,17 @@ fn linear(c: &mut Criterion) {
     }
 }
 
-criterion_group!(hnsw, hnsw_db, linear);
+criterion_group!(
+    hnsw,
+    hnsw_db,
+    hnsw_flat,
+    hnsw_graph_vec,
+    hnsw_build_from_stream,
+    hnsw_build_from_stream_latency,
+    hnsw_bidirectional,
+    hnsw_rng,
+    hnsw_furthest_queue,
+    graph_mem_links,
+    linear
+);
 criterion_main!(hnsw);
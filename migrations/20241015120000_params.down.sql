@@ -0,0 +1 @@
+DROP TABLE hawk_params;
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS hawk_params (
+    params jsonb NOT NULL,
+    id integer NOT NULL,
+    CONSTRAINT hawk_params_pkey PRIMARY KEY (id)
+);
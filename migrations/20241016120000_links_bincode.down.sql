@@ -0,0 +1 @@
+ALTER TABLE hawk_graph_links DROP COLUMN links_bincode;
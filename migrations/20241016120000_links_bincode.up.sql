@@ -0,0 +1,2 @@
+ALTER TABLE hawk_graph_links ADD COLUMN IF NOT EXISTS links_bincode bytea;
+ALTER TABLE hawk_graph_links ALTER COLUMN links DROP NOT NULL;
@@ -0,0 +1 @@
+DROP INDEX IF EXISTS hawk_graph_links_layer;
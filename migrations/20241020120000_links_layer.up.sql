@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS hawk_graph_links_layer ON hawk_graph_links (layer);
@@ -0,0 +1 @@
+DROP TABLE IF EXISTS hawk_graph_edges;
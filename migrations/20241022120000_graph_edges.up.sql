@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS hawk_graph_edges (
+    source_ref text NOT NULL,
+    layer integer NOT NULL,
+    target_ref text NOT NULL,
+    rank double precision NOT NULL,
+    distance jsonb NOT NULL,
+    CONSTRAINT hawk_graph_edges_pkey PRIMARY KEY (source_ref, layer, target_ref)
+);
@@ -0,0 +1 @@
+DROP INDEX IF EXISTS hawk_graph_edges_target;
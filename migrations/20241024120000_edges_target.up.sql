@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS hawk_graph_edges_target ON hawk_graph_edges (target_ref, layer);
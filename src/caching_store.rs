@@ -0,0 +1,357 @@
+use crate::{DataStore, UpdateStore, VectorStore};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::sync::Mutex;
+
+/// A VectorStore wrapper that memoizes distance evaluations.
+///
+/// The same (query, vector) pair can be evaluated several times, for example when a node is reachable via multiple paths
+/// in `search_layer`. This wrapper returns the cached distance instead of calling the underlying store again.
+/// All other operations are forwarded as-is.
+///
+/// When the cache is full, the least recently used distance is evicted.
+#[derive(Debug)]
+pub struct CachingStore<V: VectorStore> {
+    inner: V,
+    cache: Mutex<Cache<V>>,
+}
+
+/// A query and a vector, whose distance is cached.
+type Key<V> = (<V as VectorStore>::QueryRef, <V as VectorStore>::VectorRef);
+
+#[derive(Debug, Clone)]
+struct Cache<V: VectorStore> {
+    capacity: usize,
+    /// The distance of each `(query, vector)`, with the time of its last use.
+    distances: HashMap<Key<V>, (V::DistanceRef, u64)>,
+    /// The keys of `distances` by time of last use.
+    recency: BTreeMap<u64, Key<V>>,
+    clock: u64,
+    hits: usize,
+    misses: usize,
+}
+
+impl<V: VectorStore> Cache<V> {
+    fn get(&mut self, key: &Key<V>) -> Option<V::DistanceRef> {
+        let (distance, used) = self.distances.get_mut(key)?;
+        self.clock += 1;
+        let key = self
+            .recency
+            .remove(used)
+            .expect("a cached key has a time of use");
+        *used = self.clock;
+        self.recency.insert(self.clock, key);
+        Some(distance.clone())
+    }
+
+    fn insert(&mut self, key: Key<V>, distance: V::DistanceRef) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some((_, used)) = self.distances.remove(&key) {
+            self.recency.remove(&used);
+        }
+        while self.distances.len() >= self.capacity {
+            let (_, oldest) = self.recency.pop_first().expect("a full cache is not empty");
+            self.distances.remove(&oldest);
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, key.clone());
+        self.distances.insert(key, (distance, self.clock));
+    }
+
+    fn remove_vector(&mut self, vector: &V::VectorRef) {
+        self.distances.retain(|(_, v), _| v != vector);
+        self.recency.retain(|_, (_, v)| v != vector);
+    }
+
+    fn clear(&mut self) {
+        self.distances.clear();
+        self.recency.clear();
+    }
+}
+
+impl<V: VectorStore> CachingStore<V> {
+    /// Wrap a store with a cache of at most `capacity` distances. A capacity of 0 disables the cache.
+    pub fn new(inner: V, capacity: usize) -> Self {
+        CachingStore {
+            inner,
+            cache: Mutex::new(Cache {
+                capacity,
+                distances: HashMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    pub fn inner(&self) -> &V {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut V {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> V {
+        self.inner
+    }
+
+    /// The number of distances in the cache.
+    pub fn cache_len(&self) -> usize {
+        self.cache.lock().unwrap().distances.len()
+    }
+
+    /// The number of distances served from the cache.
+    pub fn cache_hits(&self) -> usize {
+        self.cache.lock().unwrap().hits
+    }
+
+    /// The number of distances evaluated by the underlying store.
+    pub fn cache_misses(&self) -> usize {
+        self.cache.lock().unwrap().misses
+    }
+
+    /// Drop all the cached distances, e.g. between batches of unrelated queries.
+    pub fn clear_cache(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+}
+
+impl<V: VectorStore> Clone for CachingStore<V> {
+    fn clone(&self) -> Self {
+        CachingStore {
+            inner: self.inner.clone(),
+            cache: Mutex::new(self.cache.lock().unwrap().clone()),
+        }
+    }
+}
+
+impl<V: VectorStore> VectorStore for CachingStore<V> {
+    type QueryRef = V::QueryRef;
+    type VectorRef = V::VectorRef;
+    type DistanceRef = V::DistanceRef;
+
+    const CHECK_SORTED: bool = V::CHECK_SORTED;
+
+    async fn insert(&mut self, query: &Self::QueryRef) -> Self::VectorRef {
+        self.inner.insert(query).await
+    }
+
+    async fn eval_distance(
+        &self,
+        query: &Self::QueryRef,
+        vector: &Self::VectorRef,
+    ) -> Self::DistanceRef {
+        self.eval_distance_batch(query, std::slice::from_ref(vector))
+            .await
+            .pop()
+            .unwrap()
+    }
+
+    async fn eval_distance_rev(
+        &self,
+        vector: &Self::VectorRef,
+        inserted: &Self::VectorRef,
+        distance: &Self::DistanceRef,
+    ) -> Self::DistanceRef {
+        self.inner
+            .eval_distance_rev(vector, inserted, distance)
+            .await
+    }
+
+    async fn is_match(&self, distance: &Self::DistanceRef) -> bool {
+        self.inner.is_match(distance).await
+    }
+
+    async fn less_than(
+        &self,
+        distance1: &Self::DistanceRef,
+        distance2: &Self::DistanceRef,
+    ) -> bool {
+        self.inner.less_than(distance1, distance2).await
+    }
+
+    fn tie_break(&self, vector1: &Self::VectorRef, vector2: &Self::VectorRef) -> Ordering {
+        self.inner.tie_break(vector1, vector2)
+    }
+
+    async fn search_sorted(
+        &self,
+        distances: &[Self::DistanceRef],
+        target: &Self::DistanceRef,
+    ) -> usize {
+        self.inner.search_sorted(distances, target).await
+    }
+
+    async fn argsort(&self, distances: &[Self::DistanceRef]) -> Vec<usize> {
+        self.inner.argsort(distances).await
+    }
+
+    async fn insert_batch(&mut self, queries: &[Self::QueryRef]) -> Vec<Self::VectorRef> {
+        self.inner.insert_batch(queries).await
+    }
+
+    async fn eval_distance_batch(
+        &self,
+        query: &Self::QueryRef,
+        vectors: &[Self::VectorRef],
+    ) -> Vec<Self::DistanceRef> {
+        // Look up the cache, and collect the vectors that must be evaluated.
+        let mut results = Vec::with_capacity(vectors.len());
+        let mut missing = vec![];
+        {
+            let mut cache = self.cache.lock().unwrap();
+            for vector in vectors {
+                let key = (query.clone(), vector.clone());
+                match cache.get(&key) {
+                    Some(distance) => {
+                        cache.hits += 1;
+                        results.push(Some(distance));
+                    }
+                    None => {
+                        missing.push(vector.clone());
+                        results.push(None);
+                    }
+                }
+            }
+        }
+
+        if missing.is_empty() {
+            return results.into_iter().map(Option::unwrap).collect();
+        }
+
+        let distances = self.inner.eval_distance_batch(query, &missing).await;
+
+        // Fill the cache and the results.
+        let mut cache = self.cache.lock().unwrap();
+        cache.misses += missing.len();
+        let mut distances = missing.into_iter().zip(distances);
+        results
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| {
+                    let (vector, distance) = distances.next().unwrap();
+                    cache.insert((query.clone(), vector), distance.clone());
+                    distance
+                })
+            })
+            .collect()
+    }
+
+    async fn less_than_batch(
+        &self,
+        distance: &Self::DistanceRef,
+        distances: &[Self::DistanceRef],
+    ) -> Vec<bool> {
+        self.inner.less_than_batch(distance, distances).await
+    }
+
+    async fn is_match_batch(&self, distances: &[Self::DistanceRef]) -> Vec<bool> {
+        self.inner.is_match_batch(distances).await
+    }
+}
+
+impl<V: DataStore> DataStore for CachingStore<V> {
+    type Data = V::Data;
+
+    async fn len(&self) -> usize {
+        self.inner.len().await
+    }
+
+    async fn get_data(&self, vector: &Self::VectorRef) -> Option<Self::Data> {
+        self.inner.get_data(vector).await
+    }
+
+    fn query_from_vector(&self, vector: &Self::VectorRef) -> Self::QueryRef {
+        self.inner.query_from_vector(vector)
+    }
+}
+
+impl<V: UpdateStore> UpdateStore for CachingStore<V> {
+    async fn update_data(&mut self, vector: &Self::VectorRef, query: &Self::QueryRef) {
+        // The cached distances to the vector are stale.
+        self.cache.lock().unwrap().remove_vector(vector);
+        self.inner.update_data(vector, query).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::examples::lazy_memory_store::LazyMemoryStore;
+
+    #[tokio::test]
+    async fn test_caching_store() {
+        let mut store = CachingStore::new(LazyMemoryStore::new(), 100);
+
+        let query = store.inner_mut().prepare_query_u64(1);
+        let vectors = {
+            let mut v = vec![];
+            for raw_query in 2..5 {
+                let q = store.inner_mut().prepare_query_u64(raw_query);
+                v.push(store.insert(&q).await);
+            }
+            v
+        };
+
+        let d0 = store.eval_distance(&query, &vectors[0]).await;
+        assert_eq!((store.cache_hits(), store.cache_misses()), (0, 1));
+
+        let d0_again = store.eval_distance(&query, &vectors[0]).await;
+        assert_eq!(d0, d0_again);
+        assert_eq!((store.cache_hits(), store.cache_misses()), (1, 1));
+
+        // Only the vectors not seen before reach the underlying store.
+        let batch = store.eval_distance_batch(&query, &vectors).await;
+        assert_eq!((store.cache_hits(), store.cache_misses()), (2, 3));
+
+        let expected = store.inner().eval_distance_batch(&query, &vectors).await;
+        assert_eq!(batch, expected);
+    }
+
+    #[tokio::test]
+    async fn test_caching_store_capacity() {
+        let mut store = CachingStore::new(LazyMemoryStore::new(), 2);
+
+        let query = store.inner_mut().prepare_query_u64(1);
+        let vectors = {
+            let mut v = vec![];
+            for raw_query in 2..5 {
+                let q = store.inner_mut().prepare_query_u64(raw_query);
+                v.push(store.insert(&q).await);
+            }
+            v
+        };
+
+        store.eval_distance(&query, &vectors[0]).await;
+        store.eval_distance(&query, &vectors[1]).await;
+        // Use vectors[0] again, so vectors[1] is the least recently used.
+        store.eval_distance(&query, &vectors[0]).await;
+        assert_eq!((store.cache_hits(), store.cache_misses()), (1, 2));
+
+        // A full cache evicts the least recently used distance.
+        store.eval_distance(&query, &vectors[2]).await;
+        assert_eq!(store.cache_len(), 2);
+        store.eval_distance(&query, &vectors[0]).await;
+        assert_eq!((store.cache_hits(), store.cache_misses()), (2, 3));
+        store.eval_distance(&query, &vectors[1]).await;
+        assert_eq!((store.cache_hits(), store.cache_misses()), (2, 4));
+
+        // Clearing the cache evaluates the distances again.
+        store.clear_cache();
+        assert_eq!(store.cache_len(), 0);
+        store.eval_distance(&query, &vectors[1]).await;
+        assert_eq!((store.cache_hits(), store.cache_misses()), (2, 5));
+
+        // A capacity of 0 disables the cache.
+        let store = CachingStore::new(store.into_inner(), 0);
+        store.eval_distance(&query, &vectors[0]).await;
+        store.eval_distance(&query, &vectors[0]).await;
+        assert_eq!((store.cache_hits(), store.cache_misses()), (0, 2));
+        assert_eq!(store.cache_len(), 0);
+    }
+}
@@ -0,0 +1,77 @@
+use thiserror::Error;
+
+/// The errors returned by the stores of this crate.
+///
+/// It converts into `eyre::Report`, so `?` works in functions returning `eyre::Result`.
+#[derive(Debug, Error)]
+pub enum HawkError {
+    #[error("Invalid SQL identifier: {0:?}")]
+    InvalidIdentifier(String),
+
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    /// A reference, or a value containing references, cannot be serialized to be stored.
+    #[error("Cannot serialize {context}: {source}")]
+    RefSerialization {
+        context: String,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Binary serialization error: {0}")]
+    BinarySerialization(#[from] bincode::Error),
+
+    #[error("MessagePack encoding error: {0}")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+
+    #[error("MessagePack decoding error: {0}")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+
+    #[error("Inconsistent graph: {0}")]
+    GraphInconsistent(String),
+
+    #[error("Unsupported operation: {0}")]
+    Unsupported(String),
+
+    /// An index header does not describe the stores it is loaded with.
+    #[error("Incompatible index: {0}")]
+    IncompatibleIndex(String),
+
+    #[error("Cancelled")]
+    Cancelled,
+}
+
+impl From<sqlx::migrate::MigrateError> for HawkError {
+    fn from(err: sqlx::migrate::MigrateError) -> Self {
+        HawkError::Database(err.into())
+    }
+}
+
+pub type Result<T, E = HawkError> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::examples::lazy_memory_store::LazyMemoryStore;
+    use crate::graph_store::GraphPg;
+
+    #[tokio::test]
+    async fn test_invalid_identifier() {
+        let err = GraphPg::<LazyMemoryStore>::new("postgres://localhost", "bad; schema")
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, HawkError::InvalidIdentifier(ref name) if name == "bad; schema"));
+
+        // Converts into eyre for callers using eyre::Result.
+        let report: eyre::Report = err.into();
+        assert!(report.to_string().contains("Invalid SQL identifier"));
+    }
+}
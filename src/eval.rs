@@ -0,0 +1,163 @@
+use rand::RngCore;
+use std::collections::HashSet;
+
+use crate::{error::Result, hnsw_db::HawkSearcher, linear_db::LinearDb, GraphStore, VectorStore};
+
+/// Measure the recall@k of a searcher against the exact nearest neighbors found by a brute-force scan.
+///
+/// For each query, the recall is the fraction of the k nearest neighbors of `brute.knn` that are found by
+/// `searcher.search(query, k)`, compared by `VectorRef`. Return the average recall over the queries, and the recall of
+/// each query in order.
+///
+/// Among vectors at the same distance as the k-th nearest neighbor, both searches keep the first ones by
+/// `VectorStore::tie_break`, so a search that missed one of them and found another counts a miss. See
+/// `recall_at_k_with_ties` to count it as a hit. `brute` must hold the same vectors as the
+/// searcher, e.g. a clone of its store. A query with no neighbors, in an empty store, has a recall of 1.0, and so does
+/// an empty batch of queries.
+pub async fn recall_at_k<V: VectorStore, G: GraphStore<V>, R: RngCore>(
+    searcher: &HawkSearcher<V, G, R>,
+    brute: &LinearDb<V>,
+    queries: &[V::QueryRef],
+    k: usize,
+) -> Result<(f64, Vec<f64>)> {
+    recall(searcher, brute, queries, k, false).await
+}
+
+/// Like `recall_at_k`, but a found vector is a hit if it is not further than the k-th nearest neighbor of `brute`.
+///
+/// So among equidistant vectors, either search may keep any of them, and an exhaustive search has a recall of 1.0
+/// even with ties.
+pub async fn recall_at_k_with_ties<V: VectorStore, G: GraphStore<V>, R: RngCore>(
+    searcher: &HawkSearcher<V, G, R>,
+    brute: &LinearDb<V>,
+    queries: &[V::QueryRef],
+    k: usize,
+) -> Result<(f64, Vec<f64>)> {
+    recall(searcher, brute, queries, k, true).await
+}
+
+async fn recall<V: VectorStore, G: GraphStore<V>, R: RngCore>(
+    searcher: &HawkSearcher<V, G, R>,
+    brute: &LinearDb<V>,
+    queries: &[V::QueryRef],
+    k: usize,
+    with_ties: bool,
+) -> Result<(f64, Vec<f64>)> {
+    let mut recalls = Vec::with_capacity(queries.len());
+
+    for query in queries {
+        let expected = brute.knn(query, k).await;
+        if expected.is_empty() {
+            recalls.push(1.0);
+            continue;
+        }
+
+        let found = searcher.search(query, k).await?;
+        let hits = if with_ties {
+            let (_, kth) = expected.last().expect("not empty");
+            let mut hits = 0;
+            for (_, d) in found.iter() {
+                if !searcher.vector_store.less_than(kth, d).await {
+                    hits += 1;
+                }
+            }
+            hits.min(expected.len())
+        } else {
+            let expected = expected.iter().map(|(v, _)| v).collect::<HashSet<_>>();
+            found.iter().filter(|(v, _)| expected.contains(v)).count()
+        };
+        recalls.push(hits as f64 / expected.len() as f64);
+    }
+
+    let mean = if recalls.is_empty() {
+        1.0
+    } else {
+        recalls.iter().sum::<f64>() / recalls.len() as f64
+    };
+    Ok((mean, recalls))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::examples::lazy_memory_store::LazyMemoryStore;
+    use crate::graph_store::GraphMem;
+    use crate::hnsw_db::test_utils::{insert_all, prepare_queries};
+    use crate::hnsw_db::Params;
+    use aes_prng::AesRng;
+    use rand::{Rng, SeedableRng};
+
+    type Searcher = HawkSearcher<LazyMemoryStore, GraphMem<LazyMemoryStore>>;
+
+    /// Insert the same random vectors into a searcher and a brute-force database, and return random test queries.
+    async fn build(
+        params: Params,
+        n: usize,
+    ) -> (
+        Searcher,
+        LinearDb<LazyMemoryStore>,
+        Vec<<LazyMemoryStore as VectorStore>::QueryRef>,
+    ) {
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let mut store = LazyMemoryStore::new();
+        let queries = prepare_queries(&mut store, (0..n).map(|_| rng.gen()));
+        let test_queries = prepare_queries(&mut store, (0..50).map(|_| rng.gen()));
+
+        let mut brute = LinearDb::new(store.clone());
+        let mut searcher = HawkSearcher::new_with_params(store, GraphMem::new(), params, &mut rng);
+        for query in queries.iter() {
+            assert!(brute.insert(query).await);
+        }
+        insert_all(&mut searcher, &queries).await;
+        (searcher, brute, test_queries)
+    }
+
+    #[tokio::test]
+    async fn test_recall_exhaustive() {
+        // With ef above the number of vectors, the search of layer 0 visits every vector reachable from the entry point.
+        // With sparser links, e.g. M=4, some vectors are unreachable, and the recall is below 1.0 even so.
+        let params = Params {
+            ef: 256,
+            ..Params::default()
+        };
+        let (searcher, brute, queries) = build(params, 200).await;
+
+        for k in [1, 10] {
+            let (recall, per_query) = recall_at_k_with_ties(&searcher, &brute, &queries, k)
+                .await
+                .unwrap();
+            assert_eq!(recall, 1.0);
+            assert_eq!(per_query, vec![1.0; queries.len()]);
+
+            // Both searches order equal distances by `VectorStore::tie_break`, so they keep the same vectors.
+            let (recall, per_query) = recall_at_k(&searcher, &brute, &queries, k).await.unwrap();
+            assert_eq!(recall, 1.0);
+            assert_eq!(per_query, vec![1.0; queries.len()]);
+        }
+
+        let (recall, per_query) = recall_at_k(&searcher, &brute, &[], 10).await.unwrap();
+        assert_eq!((recall, per_query), (1.0, vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_recall_default_params() {
+        let (searcher, brute, queries) = build(Params::default(), 2000).await;
+
+        let (recall, per_query) = recall_at_k_with_ties(&searcher, &brute, &queries, 10)
+            .await
+            .unwrap();
+        assert_eq!(per_query.len(), queries.len());
+        assert!(per_query.iter().all(|&r| (0.0..=1.0).contains(&r)));
+        let mean = per_query.iter().sum::<f64>() / per_query.len() as f64;
+        assert!((recall - mean).abs() < 1e-9);
+
+        // Measured: a recall@10 of 0.994 on 2000 random 64-bit codes, with 50 test queries, and 0.962 without the ties.
+        assert!(recall > 0.9, "recall@10: {}", recall);
+        let (exact_recall, _) = recall_at_k(&searcher, &brute, &queries, 10).await.unwrap();
+        assert!(
+            exact_recall > 0.8 && exact_recall <= recall,
+            "recall@10: {}",
+            exact_recall
+        );
+    }
+}
@@ -1 +1,2 @@
+pub mod asymmetric_memory_store;
 pub mod lazy_memory_store;
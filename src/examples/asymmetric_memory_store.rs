@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{DataStore, UpdateStore, VectorStore};
+
+/// Example implementation of a vector store with an asymmetric metric.
+///
+/// The distance from `a` to `b` is the number of bits set in `a` but not in `b`, so it differs from the distance from `b` to `a`.
+/// As in `LazyMemoryStore`, a distance is lazily represented as a tuple of point IDs, in the direction of evaluation.
+#[derive(Default, Clone, Debug)]
+pub struct AsymmetricMemoryStore {
+    points: Vec<Point>,
+}
+
+#[derive(Clone, Debug)]
+struct Point {
+    data: u64,
+    is_persistent: bool,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PointId(usize);
+
+impl AsymmetricMemoryStore {
+    pub fn new() -> Self {
+        AsymmetricMemoryStore { points: vec![] }
+    }
+
+    pub fn prepare_query(&mut self, raw_query: u64) -> <Self as VectorStore>::QueryRef {
+        self.points.push(Point {
+            data: raw_query,
+            is_persistent: false,
+        });
+        PointId(self.points.len() - 1)
+    }
+
+    fn actually_evaluate_distance(&self, pair: &<Self as VectorStore>::DistanceRef) -> u32 {
+        let from = self.points[pair.0 .0].data;
+        let to = self.points[pair.1 .0].data;
+        (from & !to).count_ones()
+    }
+}
+
+impl VectorStore for AsymmetricMemoryStore {
+    type QueryRef = PointId;
+    type VectorRef = PointId;
+    type DistanceRef = (PointId, PointId);
+
+    async fn insert(&mut self, query: &Self::QueryRef) -> Self::VectorRef {
+        self.points[query.0].is_persistent = true;
+        *query
+    }
+
+    async fn eval_distance(
+        &self,
+        query: &Self::QueryRef,
+        vector: &Self::VectorRef,
+    ) -> Self::DistanceRef {
+        (*query, *vector)
+    }
+
+    async fn eval_distance_rev(
+        &self,
+        vector: &Self::VectorRef,
+        inserted: &Self::VectorRef,
+        _distance: &Self::DistanceRef,
+    ) -> Self::DistanceRef {
+        (*vector, *inserted)
+    }
+
+    async fn is_match(&self, distance: &Self::DistanceRef) -> bool {
+        self.actually_evaluate_distance(distance) == 0
+    }
+
+    async fn less_than(
+        &self,
+        distance1: &Self::DistanceRef,
+        distance2: &Self::DistanceRef,
+    ) -> bool {
+        self.actually_evaluate_distance(distance1) < self.actually_evaluate_distance(distance2)
+    }
+}
+
+impl DataStore for AsymmetricMemoryStore {
+    type Data = u64;
+
+    async fn len(&self) -> usize {
+        self.points.iter().filter(|p| p.is_persistent).count()
+    }
+
+    async fn get_data(&self, vector: &Self::VectorRef) -> Option<Self::Data> {
+        self.points
+            .get(vector.0)
+            .filter(|p| p.is_persistent)
+            .map(|p| p.data)
+    }
+
+    fn query_from_vector(&self, vector: &Self::VectorRef) -> Self::QueryRef {
+        *vector
+    }
+}
+
+impl UpdateStore for AsymmetricMemoryStore {
+    async fn update_data(&mut self, vector: &Self::VectorRef, query: &Self::QueryRef) {
+        self.points[vector.0].data = self.points[query.0].data;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph_store::{GraphMem, GraphStore};
+    use crate::hnsw_db::test_utils::insert_all;
+    use crate::hnsw_db::HawkSearcher;
+    use aes_prng::AesRng;
+    use rand::SeedableRng;
+
+    #[tokio::test]
+    async fn test_back_links_use_reversed_distance() {
+        let vector_store = AsymmetricMemoryStore::new();
+        let graph_store = GraphMem::new();
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let mut db = HawkSearcher::new(vector_store, graph_store, &mut rng);
+
+        let queries = (0..20)
+            .map(|raw_query| db.vector_store.prepare_query(raw_query * 7919))
+            .collect::<Vec<_>>();
+
+        insert_all(&mut db, &queries).await;
+
+        // Every link is evaluated from its base to its neighbor, in either direction of creation.
+        let mut n_links = 0;
+        for base in queries.iter() {
+            let links = db.graph_store.get_links(base, 0).await.unwrap();
+            for (neighbor, distance) in links.iter() {
+                assert_eq!(*distance, (*base, *neighbor));
+                n_links += 1;
+            }
+        }
+        assert!(n_links > 0);
+    }
+}
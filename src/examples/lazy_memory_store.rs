@@ -1,34 +1,74 @@
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 
-use crate::VectorStore;
+use crate::{DataStore, RenumberStore, UpdateStore, VectorStore};
 
 /// Example implementation of a vector store - Lazy variant.
 ///
 /// A distance is lazily represented in `DistanceRef` as a tuple of point IDs, and the actual distance is evaluated later in `less_than`.
+///
+/// By default, an inserted vector keeps the ID of its query. With `new_with_distinct_refs`, it gets a new ID instead.
 #[derive(Default, Clone, Debug)]
 pub struct LazyMemoryStore {
     points: Vec<Point>,
+    /// The vector inserted from each query, if queries and vectors have distinct IDs.
+    vectors_of_queries: Option<HashMap<PointId, PointId>>,
 }
 
 #[derive(Clone, Debug)]
 struct Point {
-    /// Whatever encoding of a vector.
-    data: u64,
+    /// Whatever encoding of a vector, compared bitwise.
+    data: Vec<u8>,
     /// Distinguish between queries that are pending, and those that were ultimately accepted into the vector store.
     is_persistent: bool,
 }
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct PointId(usize);
 
+impl From<PointId> for usize {
+    fn from(id: PointId) -> Self {
+        id.0
+    }
+}
+
 impl LazyMemoryStore {
     pub fn new() -> Self {
-        LazyMemoryStore { points: vec![] }
+        LazyMemoryStore {
+            points: vec![],
+            vectors_of_queries: None,
+        }
+    }
+
+    /// A store where `insert` gives a new ID to the vector, distinct from the ID of the query.
+    ///
+    /// The query stays pending, and `vector_of_query` tells which vector it created.
+    pub fn new_with_distinct_refs() -> Self {
+        LazyMemoryStore {
+            points: vec![],
+            vectors_of_queries: Some(HashMap::new()),
+        }
+    }
+
+    /// The vector inserted from a query, or None if the query was not inserted.
+    ///
+    /// If a query was inserted several times with distinct IDs, this is the last vector.
+    pub fn vector_of_query(&self, query: &<Self as VectorStore>::QueryRef) -> Option<PointId> {
+        match &self.vectors_of_queries {
+            Some(vectors_of_queries) => vectors_of_queries.get(query).copied(),
+            None => self
+                .points
+                .get(query.0)
+                .filter(|p| p.is_persistent)
+                .map(|_| *query),
+        }
     }
 }
 
 impl LazyMemoryStore {
-    pub fn prepare_query(&mut self, raw_query: u64) -> <Self as VectorStore>::QueryRef {
+    /// Prepare a query from a code of any length. All codes of a store must have the same length.
+    pub fn prepare_query(&mut self, raw_query: Vec<u8>) -> <Self as VectorStore>::QueryRef {
         self.points.push(Point {
             data: raw_query,
             is_persistent: false,
@@ -38,11 +78,42 @@ impl LazyMemoryStore {
         PointId(point_id)
     }
 
-    fn actually_evaluate_distance(&self, pair: &<Self as VectorStore>::DistanceRef) -> u32 {
-        // Hamming distance
-        let vector_0 = self.points[pair.0 .0].data;
-        let vector_1 = self.points[pair.1 .0].data;
-        (vector_0 ^ vector_1).count_ones()
+    /// Prepare many queries at once, like `prepare_query` in a loop, with the storage reserved up front.
+    ///
+    /// The queries get consecutive IDs, in the order of `raw_queries`.
+    pub fn prepare_query_batch(
+        &mut self,
+        raw_queries: Vec<Vec<u8>>,
+    ) -> Vec<<Self as VectorStore>::QueryRef> {
+        self.points.reserve(raw_queries.len());
+        raw_queries
+            .into_iter()
+            .map(|raw_query| self.prepare_query(raw_query))
+            .collect()
+    }
+
+    /// Prepare a query from a 64-bit code.
+    pub fn prepare_query_u64(&mut self, raw_query: u64) -> <Self as VectorStore>::QueryRef {
+        self.prepare_query(raw_query.to_le_bytes().to_vec())
+    }
+
+    /// The Hamming distance, up to the number of bits of the codes, e.g. 512 for 64-byte codes.
+    pub(crate) fn actually_evaluate_distance(
+        &self,
+        pair: &<Self as VectorStore>::DistanceRef,
+    ) -> u32 {
+        let vector_0 = &self.points[pair.0 .0].data;
+        let vector_1 = &self.points[pair.1 .0].data;
+        assert_eq!(
+            vector_0.len(),
+            vector_1.len(),
+            "cannot compare codes of different lengths"
+        );
+        vector_0
+            .iter()
+            .zip(vector_1)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum()
     }
 }
 
@@ -52,9 +123,20 @@ impl VectorStore for LazyMemoryStore {
     type DistanceRef = (PointId, PointId); // Lazy distance representation.
 
     async fn insert(&mut self, query: &Self::QueryRef) -> Self::VectorRef {
-        // The query is now accepted in the store. It keeps the same ID.
-        self.points[query.0].is_persistent = true;
-        *query
+        let Some(vectors_of_queries) = self.vectors_of_queries.as_mut() else {
+            // The query is now accepted in the store. It keeps the same ID.
+            self.points[query.0].is_persistent = true;
+            return *query;
+        };
+
+        // A copy of the query is accepted in the store, under a new ID.
+        let vector = PointId(self.points.len());
+        self.points.push(Point {
+            data: self.points[query.0].data.clone(),
+            is_persistent: true,
+        });
+        vectors_of_queries.insert(*query, vector);
+        vector
     }
 
     async fn eval_distance(
@@ -77,24 +159,354 @@ impl VectorStore for LazyMemoryStore {
     ) -> bool {
         self.actually_evaluate_distance(distance1) < self.actually_evaluate_distance(distance2)
     }
+
+    fn tie_break(&self, vector1: &Self::VectorRef, vector2: &Self::VectorRef) -> Ordering {
+        vector1.cmp(vector2)
+    }
+
+    async fn argsort(&self, distances: &[Self::DistanceRef]) -> Vec<usize> {
+        // Evaluate each distance once. The sort is stable, as required.
+        let mut indices = (0..distances.len()).collect::<Vec<_>>();
+        indices.sort_by_cached_key(|&i| self.actually_evaluate_distance(&distances[i]));
+        indices
+    }
+}
+
+impl DataStore for LazyMemoryStore {
+    type Data = Vec<u8>; // The code given to `prepare_query`.
+
+    async fn len(&self) -> usize {
+        self.points.iter().filter(|p| p.is_persistent).count()
+    }
+
+    async fn get_data(&self, vector: &Self::VectorRef) -> Option<Self::Data> {
+        self.points
+            .get(vector.0)
+            .filter(|p| p.is_persistent)
+            .map(|p| p.data.clone())
+    }
+
+    fn query_from_vector(&self, vector: &Self::VectorRef) -> Self::QueryRef {
+        // A query and a vector with the same ID have the same data.
+        *vector
+    }
+}
+
+impl UpdateStore for LazyMemoryStore {
+    async fn update_data(&mut self, vector: &Self::VectorRef, query: &Self::QueryRef) {
+        // The lazy distances of the vector are evaluated from the new data from now on.
+        self.points[vector.0].data = self.points[query.0].data.clone();
+    }
+}
+
+impl RenumberStore for LazyMemoryStore {
+    async fn renumber(&mut self, keep: &HashSet<PointId>) -> HashMap<PointId, PointId> {
+        let mut mapping = HashMap::new();
+        let points = std::mem::take(&mut self.points);
+        for (i, point) in points.into_iter().enumerate() {
+            if point.is_persistent && keep.contains(&PointId(i)) {
+                mapping.insert(PointId(i), PointId(self.points.len()));
+                self.points.push(point);
+            }
+        }
+        // The queries are removed.
+        if let Some(vectors_of_queries) = self.vectors_of_queries.as_mut() {
+            vectors_of_queries.clear();
+        }
+        mapping
+    }
+
+    fn renumber_distance(
+        &self,
+        _distance: &(PointId, PointId),
+        base: &PointId,
+        neighbor: &PointId,
+    ) -> (PointId, PointId) {
+        // The distance of a link is the distance between its vectors. It may have been evaluated with a query that
+        // is now removed, e.g. by `HawkSearcher::update`, so refer to the vectors instead.
+        (*base, *neighbor)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::graph_store::GraphMem;
+    use crate::hnsw_db::test_utils::{insert_all, prepare_queries, SortChecked};
+    use crate::hnsw_db::HawkSearcher;
+    use crate::instrumented_store::InstrumentedStore;
 
     #[tokio::test]
     async fn test_eval_distance() {
         let mut store = LazyMemoryStore::new();
 
-        let query = store.prepare_query(11);
+        let query = store.prepare_query_u64(11);
         let vector = store.insert(&query).await;
         let distance = store.eval_distance(&query, &vector).await;
         assert!(store.is_match(&distance).await);
 
-        let other_query = store.prepare_query(22);
+        let other_query = store.prepare_query_u64(22);
         let other_vector = store.insert(&other_query).await;
         let other_distance = store.eval_distance(&query, &other_vector).await;
         assert!(!store.is_match(&other_distance).await);
     }
+
+    #[tokio::test]
+    async fn test_prepare_query_batch() {
+        let mut store = LazyMemoryStore::new();
+        let first = store.prepare_query_u64(0);
+
+        let n = 10_000;
+        let codes = (1..=n as u64).collect::<Vec<_>>();
+        let queries = store.prepare_query_batch(
+            codes
+                .iter()
+                .map(|code| code.to_le_bytes().to_vec())
+                .collect(),
+        );
+        assert_eq!(queries, (1..=n).map(PointId).collect::<Vec<_>>());
+        assert!(store.prepare_query_batch(vec![]).is_empty());
+
+        // The queries are the same as prepared one at a time.
+        for (query, code) in queries.iter().zip(codes.iter()).step_by(97) {
+            let single = store.prepare_query_u64(*code);
+            let distance = store.eval_distance(query, &single).await;
+            assert!(store.is_match(&distance).await);
+            let vector = store.insert(query).await;
+            assert_eq!(
+                store.get_data(&vector).await,
+                Some(code.to_le_bytes().to_vec())
+            );
+        }
+        assert_eq!(store.get_data(&first).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_distinct_refs() {
+        let mut store = LazyMemoryStore::new();
+        let query = store.prepare_query_u64(11);
+        assert_eq!(store.vector_of_query(&query), None);
+        let vector = store.insert(&query).await;
+        assert_eq!(vector, query);
+        assert_eq!(store.vector_of_query(&query), Some(vector));
+
+        let mut store = LazyMemoryStore::new_with_distinct_refs();
+        let query = store.prepare_query_u64(11);
+        let other_query = store.prepare_query_u64(22);
+        assert_eq!(store.vector_of_query(&query), None);
+
+        let vector = store.insert(&query).await;
+        assert_ne!(vector, query);
+        assert_ne!(vector, other_query);
+        assert_eq!(store.vector_of_query(&query), Some(vector));
+        assert_eq!(store.vector_of_query(&other_query), None);
+
+        // The query stays pending, and the vector has its data.
+        assert_eq!(store.len().await, 1);
+        assert_eq!(store.get_data(&query).await, None);
+        assert_eq!(
+            store.get_data(&vector).await,
+            Some(11_u64.to_le_bytes().to_vec())
+        );
+        let distance = store.eval_distance(&query, &vector).await;
+        assert!(store.is_match(&distance).await);
+
+        // A searcher finds the vectors created from the queries.
+        let mut db = HawkSearcher::from_seed(store, GraphMem::new(), 0);
+        let queries = (0..100_u64)
+            .map(|raw_query| db.vector_store.prepare_query_u64(raw_query * 7919))
+            .collect::<Vec<_>>();
+        insert_all(&mut db, &queries).await;
+        for query in queries.iter() {
+            let vector = db.vector_store.vector_of_query(query).unwrap();
+            assert_ne!(vector, *query);
+            assert_eq!(db.search(query, 1).await.unwrap()[0].0, vector);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_eval_distance_cross() {
+        let mut store = LazyMemoryStore::new();
+        let queries = prepare_queries(&mut store, 0..3);
+        let mut vectors = vec![];
+        for raw_vector in [5, 6, 7, 8] {
+            let query = store.prepare_query_u64(raw_vector);
+            vectors.push(store.insert(&query).await);
+        }
+
+        let cross = store.eval_distance_cross(&queries, &vectors).await;
+        assert_eq!(cross.len(), queries.len());
+        for (query, row) in queries.iter().zip(cross) {
+            assert_eq!(row.len(), vectors.len());
+            for (vector, distance) in vectors.iter().zip(row) {
+                let expected = store.eval_distance(query, vector).await;
+                assert_eq!(
+                    store.actually_evaluate_distance(&distance),
+                    store.actually_evaluate_distance(&expected)
+                );
+            }
+        }
+
+        assert!(store.eval_distance_cross(&[], &vectors).await.is_empty());
+        assert_eq!(
+            store.eval_distance_cross(&queries, &[]).await,
+            vec![vec![]; queries.len()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_long_codes() {
+        let mut store = LazyMemoryStore::new();
+
+        // 256-bit codes.
+        let code = (0..32).collect::<Vec<u8>>();
+        let mut other_code = code.clone();
+        other_code[0] ^= 0b11;
+        other_code[31] ^= 0b1;
+
+        let query = store.prepare_query(code);
+        let vector = store.insert(&query).await;
+        let other_query = store.prepare_query(other_code);
+        let other_vector = store.insert(&other_query).await;
+
+        assert_eq!(store.actually_evaluate_distance(&(query, other_vector)), 3);
+        assert!(
+            store
+                .is_match(&store.eval_distance(&query, &vector).await)
+                .await
+        );
+        assert!(
+            !store
+                .is_match(&store.eval_distance(&query, &other_vector).await)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wide_codes() {
+        let mut store = LazyMemoryStore::new();
+
+        // 512-bit codes, at every distance from the query: the first d bits are flipped.
+        let code = vec![0b1010_1010_u8; 64];
+        let query = store.prepare_query(code.clone());
+        let mut distances = vec![];
+        for d in (0..=512).rev() {
+            let mut other_code = code.clone();
+            for bit in 0..d {
+                other_code[bit / 8] ^= 1 << (bit % 8);
+            }
+            let other_query = store.prepare_query(other_code);
+            let vector = store.insert(&other_query).await;
+            distances.push(store.eval_distance(&query, &vector).await);
+        }
+
+        assert_eq!(store.actually_evaluate_distance(&distances[0]), 512);
+        assert!(store.is_match(&distances[512]).await);
+        assert!(!store.is_match(&distances[511]).await);
+
+        // The distances were created in descending order.
+        for pair in distances.windows(2) {
+            assert!(store.less_than(&pair[1], &pair[0]).await);
+            assert!(!store.less_than(&pair[0], &pair[1]).await);
+        }
+        distances.reverse();
+        assert_eq!(store.search_sorted(&distances, &distances[300]).await, 300);
+    }
+
+    #[tokio::test]
+    async fn test_argsort() {
+        let mut store = LazyMemoryStore::new();
+        let query = store.prepare_query_u64(0);
+        let mut distances = vec![];
+        for raw_vector in [0b111, 0b1, 0b1111, 0, 0b11, 0b10, 0b11111, 0b100, 0b1010, 0] {
+            let q = store.prepare_query_u64(raw_vector);
+            let v = store.insert(&q).await;
+            distances.push(store.eval_distance(&query, &v).await);
+        }
+
+        let order = store.argsort(&distances).await;
+        let sorted = order
+            .iter()
+            .map(|&i| store.actually_evaluate_distance(&distances[i]))
+            .collect::<Vec<_>>();
+        assert_eq!(sorted, [0, 0, 1, 1, 1, 2, 2, 3, 4, 5]);
+        // Equal distances keep their order.
+        assert_eq!(order, [3, 9, 1, 5, 7, 4, 8, 0, 2, 6]);
+
+        // The default implementation, with comparisons, gives the same permutation.
+        let instrumented = InstrumentedStore::new(store.clone());
+        assert_eq!(instrumented.argsort(&distances).await, order);
+        assert!(instrumented.less_than_count() > 0);
+
+        for n in 0..distances.len() {
+            assert_eq!(
+                instrumented.argsort(&distances[..n]).await,
+                store.argsort(&distances[..n]).await
+            );
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "ascending order")]
+    async fn test_search_sorted_unsorted() {
+        let mut store = LazyMemoryStore::new();
+        let query = store.prepare_query_u64(0);
+        let mut distances = vec![];
+        for raw_query in [0b1, 0b111, 0b11] {
+            let q = store.prepare_query_u64(raw_query);
+            let v = store.insert(&q).await;
+            distances.push(store.eval_distance(&query, &v).await);
+        }
+
+        // Not checked by default.
+        assert_eq!(store.search_sorted(&distances, &distances[0]).await, 0);
+
+        SortChecked(store)
+            .search_sorted(&distances, &distances[0])
+            .await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "different lengths")]
+    async fn test_length_mismatch() {
+        let mut store = LazyMemoryStore::new();
+        let query = store.prepare_query(vec![0; 32]);
+        let short_query = store.prepare_query(vec![0; 8]);
+        let vector = store.insert(&short_query).await;
+        let distance = store.eval_distance(&query, &vector).await;
+        store.is_match(&distance).await;
+    }
+
+    #[tokio::test]
+    async fn test_len() {
+        let mut store = LazyMemoryStore::new();
+        assert!(store.is_empty().await);
+
+        let queries = prepare_queries(&mut store, 0..5);
+        assert_eq!(store.len().await, 0);
+
+        for query in queries[..3].iter() {
+            store.insert(query).await;
+        }
+        assert_eq!(store.len().await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_get_data() {
+        let mut db = HawkSearcher::from_seed(LazyMemoryStore::new(), GraphMem::new(), 0);
+
+        let queries = prepare_queries(&mut db.vector_store, (0..20_u64).map(|i| i * 7919));
+        insert_all(&mut db, &queries).await;
+
+        // Find a known vector, and read its code back.
+        let code = (5 * 7919_u64).to_le_bytes().to_vec();
+        let query = db.vector_store.prepare_query(code.clone());
+        let neighbors = db.search_to_insert(&query).await.unwrap();
+        let (nearest, _) = neighbors[0].get_nearest().unwrap();
+        assert_eq!(db.vector_store.get_data(nearest).await, Some(code));
+
+        // Pending queries are not stored vectors.
+        assert_eq!(db.vector_store.get_data(&query).await, None);
+    }
 }
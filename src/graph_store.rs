@@ -1,24 +1,230 @@
 use serde::{Deserialize, Serialize};
 
+use crate::error::{HawkError, Result};
 use crate::hnsw_db::FurthestQueueV;
 use crate::VectorStore;
+use std::collections::HashSet;
 use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+mod cached_graph_pg;
+pub mod concurrent_graph_mem;
 pub mod graph_mem;
 mod graph_pg;
+pub mod graph_vec;
+pub use cached_graph_pg::CachedGraphPg;
+pub use concurrent_graph_mem::ConcurrentGraphMem;
 pub use graph_mem::GraphMem;
-pub use graph_pg::{test_utils::TestGraphPg, GraphPg};
+pub use graph_pg::{test_utils::TestGraphPg, GraphPg, LinksEncoding, RetryConfig};
+pub use graph_vec::GraphVec;
 
 #[allow(async_fn_in_trait)]
 pub trait GraphStore<V: VectorStore> {
-    async fn get_entry_point(&self) -> Option<EntryPoint<V::VectorRef>>;
+    async fn get_entry_point(&self) -> Result<Option<EntryPoint<V::VectorRef>>>;
 
-    async fn set_entry_point(&mut self, entry_point: EntryPoint<V::VectorRef>);
+    async fn set_entry_point(&mut self, entry_point: EntryPoint<V::VectorRef>) -> Result<()>;
 
-    async fn get_links(&self, base: &<V as VectorStore>::VectorRef, lc: usize)
-        -> FurthestQueueV<V>;
+    async fn get_links(
+        &self,
+        base: &<V as VectorStore>::VectorRef,
+        lc: usize,
+    ) -> Result<FurthestQueueV<V>>;
 
-    async fn set_links(&mut self, base: V::VectorRef, links: FurthestQueueV<V>, lc: usize);
+    /// Get the links of a batch of vectors in the same layer.
+    /// The default implementation is a loop over `get_links`.
+    /// Override for more efficient batch lookups.
+    async fn get_links_batch(
+        &self,
+        bases: &[<V as VectorStore>::VectorRef],
+        lc: usize,
+    ) -> Result<Vec<FurthestQueueV<V>>> {
+        let mut results = Vec::with_capacity(bases.len());
+        for base in bases {
+            results.push(self.get_links(base, lc).await?);
+        }
+        Ok(results)
+    }
+
+    /// Call `f` with the links of a vector, and return its result.
+    /// The default implementation calls `f` on a copy from `get_links`.
+    /// Override for stores that can lend their links without copying.
+    async fn with_links<R>(
+        &self,
+        base: &<V as VectorStore>::VectorRef,
+        lc: usize,
+        f: impl FnOnce(&FurthestQueueV<V>) -> R,
+    ) -> Result<R> {
+        Ok(f(&self.get_links(base, lc).await?))
+    }
+
+    /// Call `f` with the links of each vector of a batch in the same layer, and return the results in order.
+    /// The default implementation calls `f` on copies from `get_links_batch`.
+    /// Override for stores that can lend their links without copying.
+    async fn with_links_batch<R>(
+        &self,
+        bases: &[<V as VectorStore>::VectorRef],
+        lc: usize,
+        f: impl FnMut(&FurthestQueueV<V>) -> R,
+    ) -> Result<Vec<R>> {
+        Ok(self
+            .get_links_batch(bases, lc)
+            .await?
+            .iter()
+            .map(f)
+            .collect())
+    }
+
+    /// The number of vectors with links in the given layer.
+    /// The default implementation returns `HawkError::Unsupported`, for stores that cannot count their vectors.
+    async fn link_count(&self, _lc: usize) -> Result<usize> {
+        Err(HawkError::Unsupported("link_count".to_string()))
+    }
+
+    /// The number of links of a vector in each layer of the graph, 0 where it has no links.
+    /// The default implementation is a loop over `get_links` up to the layer count of the entry point.
+    async fn node_degree(&self, base: &<V as VectorStore>::VectorRef) -> Result<Vec<usize>> {
+        let layer_count = self
+            .get_entry_point()
+            .await?
+            .map_or(0, |entry_point| entry_point.layer_count);
+        let mut degrees = Vec::with_capacity(layer_count);
+        for lc in 0..layer_count {
+            degrees.push(self.get_links(base, lc).await?.len());
+        }
+        Ok(degrees)
+    }
+
+    /// The links of a vector in each layer of the graph, empty where it has no links.
+    /// The default implementation is a loop over `get_links` up to the layer count of the entry point.
+    async fn get_all_links(
+        &self,
+        base: &<V as VectorStore>::VectorRef,
+    ) -> Result<Vec<FurthestQueueV<V>>> {
+        let layer_count = self
+            .get_entry_point()
+            .await?
+            .map_or(0, |entry_point| entry_point.layer_count);
+        let mut links = Vec::with_capacity(layer_count);
+        for lc in 0..layer_count {
+            links.push(self.get_links(base, lc).await?);
+        }
+        Ok(links)
+    }
+
+    /// The vectors that link to `target` in a layer, in no particular order.
+    ///
+    /// The links are stored by source, so this scans the layer, e.g. to repair the graph after a deletion.
+    /// The default implementation returns `HawkError::Unsupported`, for stores that cannot enumerate their sources.
+    async fn get_inbound(
+        &self,
+        _target: &<V as VectorStore>::VectorRef,
+        _lc: usize,
+    ) -> Result<Vec<V::VectorRef>> {
+        Err(HawkError::Unsupported("get_inbound".to_string()))
+    }
+
+    async fn set_links(
+        &mut self,
+        base: V::VectorRef,
+        links: FurthestQueueV<V>,
+        lc: usize,
+    ) -> Result<()>;
+
+    /// Set the links of a batch of vectors in the same layer.
+    /// The default implementation is a loop over `set_links`.
+    /// Override for more efficient batch writes.
+    async fn set_links_batch(
+        &mut self,
+        links: Vec<(V::VectorRef, FurthestQueueV<V>)>,
+        lc: usize,
+    ) -> Result<()> {
+        for (base, links) in links {
+            self.set_links(base, links, lc).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove all the links and the entry point, leaving an empty graph, e.g. to rebuild an index.
+    /// The default implementation returns `HawkError::Unsupported`, for stores that cannot be cleared.
+    async fn clear(&mut self) -> Result<()> {
+        Err(HawkError::Unsupported("clear".to_string()))
+    }
+
+    // Transactions.
+
+    /// Start a transaction: the following writes are applied together by `commit`, or discarded by `rollback`.
+    /// The default implementation does nothing, for stores whose writes are applied immediately and cannot fail.
+    async fn begin(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Apply the writes of the transaction started by `begin`.
+    async fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Discard the writes of the transaction started by `begin`.
+    async fn rollback(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Panic if the links of `base` link to `base` itself, or twice to the same vector, in debug builds.
+///
+/// The graph stores call this from `set_links`, before the links corrupt the graph. They cannot compare the distances,
+/// nor know the degree of a layer, so `HawkSearcher::apply_plan` checks the order and the length of the links.
+pub(crate) fn debug_check_links<V: VectorStore>(
+    base: &V::VectorRef,
+    links: &FurthestQueueV<V>,
+    lc: usize,
+) {
+    if cfg!(debug_assertions) {
+        let mut seen = HashSet::with_capacity(links.len());
+        for (neighbor, _) in links.iter() {
+            assert!(
+                neighbor != base,
+                "the links of {:?} in layer {} link to itself",
+                base,
+                lc
+            );
+            assert!(
+                seen.insert(neighbor),
+                "the links of {:?} in layer {} link twice to {:?}",
+                base,
+                lc,
+                neighbor
+            );
+        }
+    }
+}
+
+/// A warning printed by the in-memory graphs the first time `get_links` reads a layer that they do not have, e.g.
+/// when the entry point claims more layers than were loaded. The searches treat such a layer as empty, so they work,
+/// and the warning is not repeated for every visit.
+#[derive(Debug, Default)]
+pub(crate) struct MissingLayerWarning(AtomicBool);
+
+impl MissingLayerWarning {
+    pub(crate) fn warn(&self, lc: usize, layer_count: usize) {
+        if !self.0.swap(true, Ordering::Relaxed) {
+            eprintln!(
+                "warning: get_links on layer {} but the graph has only {} layers",
+                lc, layer_count
+            );
+        }
+    }
+
+    /// Whether the warning was printed.
+    #[cfg(test)]
+    pub(crate) fn warned(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Clone for MissingLayerWarning {
+    fn clone(&self) -> Self {
+        MissingLayerWarning(AtomicBool::new(self.0.load(Ordering::Relaxed)))
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -0,0 +1,350 @@
+use super::{EntryPoint, GraphPg, GraphStore};
+use crate::{error::Result, hnsw_db::FurthestQueueV, VectorStore};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+/// A GraphPg wrapper that keeps the most recently used lists of links in memory.
+///
+/// Unlike `GraphPg::to_graph_mem`, nothing is loaded up front: the searches read the links from the database, and the
+/// cache warms up with the parts of the graph they visit. When the cache is full, the least recently used list is
+/// evicted. A list is invalidated when it is written, so the next read fetches it again.
+///
+/// The writes of a transaction are not visible to the reads until `commit`, like in GraphPg, so their lists are
+/// invalidated again by `commit` and `rollback`. The cache is not shared: the writes of other stores to the same schema
+/// are not seen until the lists are evicted.
+pub struct CachedGraphPg<V: VectorStore> {
+    inner: GraphPg<V>,
+    cache: Mutex<LinksCache<V>>,
+    /// The lists written in the current transaction.
+    written: HashSet<(V::VectorRef, usize)>,
+}
+
+struct LinksCache<V: VectorStore> {
+    capacity: usize,
+    /// The links of each `(base, layer)`, with the time of their last use.
+    links: HashMap<(V::VectorRef, usize), (FurthestQueueV<V>, u64)>,
+    /// The keys of `links` by time of last use.
+    recency: BTreeMap<u64, (V::VectorRef, usize)>,
+    clock: u64,
+    hits: usize,
+    misses: usize,
+}
+
+impl<V: VectorStore> LinksCache<V> {
+    fn get(&mut self, key: &(V::VectorRef, usize)) -> Option<FurthestQueueV<V>> {
+        let Some((links, used)) = self.links.get_mut(key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        self.clock += 1;
+        let key = self
+            .recency
+            .remove(used)
+            .expect("a cached key has a time of use");
+        *used = self.clock;
+        self.recency.insert(self.clock, key);
+        Some(links.clone())
+    }
+
+    fn insert(&mut self, key: (V::VectorRef, usize), links: FurthestQueueV<V>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        while self.links.len() >= self.capacity {
+            let (_, oldest) = self.recency.pop_first().expect("a full cache is not empty");
+            self.links.remove(&oldest);
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, key.clone());
+        self.links.insert(key, (links, self.clock));
+    }
+
+    fn remove(&mut self, key: &(V::VectorRef, usize)) {
+        if let Some((_, used)) = self.links.remove(key) {
+            self.recency.remove(&used);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.links.clear();
+        self.recency.clear();
+    }
+}
+
+impl<V: VectorStore> CachedGraphPg<V> {
+    /// Wrap a store with a cache of at most `capacity` lists of links. A capacity of 0 disables the cache.
+    pub fn new(inner: GraphPg<V>, capacity: usize) -> Self {
+        CachedGraphPg {
+            inner,
+            cache: Mutex::new(LinksCache {
+                capacity,
+                links: HashMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+                hits: 0,
+                misses: 0,
+            }),
+            written: HashSet::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &GraphPg<V> {
+        &self.inner
+    }
+
+    /// The wrapped store. Its writes bypass the cache, so call `clear_cache` after writing directly.
+    pub fn inner_mut(&mut self) -> &mut GraphPg<V> {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> GraphPg<V> {
+        self.inner
+    }
+
+    /// The number of lists of links in the cache.
+    pub fn cache_len(&self) -> usize {
+        self.cache.lock().unwrap().links.len()
+    }
+
+    /// The number of lists of links served from the cache.
+    pub fn cache_hits(&self) -> usize {
+        self.cache.lock().unwrap().hits
+    }
+
+    /// The number of lists of links read from the database.
+    pub fn cache_misses(&self) -> usize {
+        self.cache.lock().unwrap().misses
+    }
+
+    /// Drop all the cached lists, e.g. after other stores wrote to the same schema.
+    pub fn clear_cache(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    fn invalidate_written(&mut self) {
+        let mut cache = self.cache.lock().unwrap();
+        for key in self.written.drain() {
+            cache.remove(&key);
+        }
+    }
+}
+
+impl<V: VectorStore> GraphStore<V> for CachedGraphPg<V> {
+    async fn get_entry_point(&self) -> Result<Option<EntryPoint<V::VectorRef>>> {
+        self.inner.get_entry_point().await
+    }
+
+    async fn set_entry_point(&mut self, entry_point: EntryPoint<V::VectorRef>) -> Result<()> {
+        self.inner.set_entry_point(entry_point).await
+    }
+
+    async fn get_links(
+        &self,
+        base: &<V as VectorStore>::VectorRef,
+        lc: usize,
+    ) -> Result<FurthestQueueV<V>> {
+        let key = (base.clone(), lc);
+        if let Some(links) = self.cache.lock().unwrap().get(&key) {
+            return Ok(links);
+        }
+
+        let links = self.inner.get_links(base, lc).await?;
+        self.cache.lock().unwrap().insert(key, links.clone());
+        Ok(links)
+    }
+
+    /// Serve the cached lists, and read the others from the database in one batch.
+    async fn get_links_batch(
+        &self,
+        bases: &[<V as VectorStore>::VectorRef],
+        lc: usize,
+    ) -> Result<Vec<FurthestQueueV<V>>> {
+        let mut results = {
+            let mut cache = self.cache.lock().unwrap();
+            bases
+                .iter()
+                .map(|base| cache.get(&(base.clone(), lc)))
+                .collect::<Vec<_>>()
+        };
+
+        let missing = bases
+            .iter()
+            .zip(results.iter())
+            .filter(|(_, links)| links.is_none())
+            .map(|(base, _)| base.clone())
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(results.into_iter().map(Option::unwrap).collect());
+        }
+
+        let mut fetched = self.inner.get_links_batch(&missing, lc).await?.into_iter();
+        let mut cache = self.cache.lock().unwrap();
+        for (base, links) in bases.iter().zip(results.iter_mut()) {
+            if links.is_none() {
+                let fetched = fetched.next().expect("one list per missing base");
+                cache.insert((base.clone(), lc), fetched.clone());
+                *links = Some(fetched);
+            }
+        }
+        Ok(results.into_iter().map(Option::unwrap).collect())
+    }
+
+    async fn link_count(&self, lc: usize) -> Result<usize> {
+        self.inner.link_count(lc).await
+    }
+
+    async fn node_degree(&self, base: &<V as VectorStore>::VectorRef) -> Result<Vec<usize>> {
+        self.inner.node_degree(base).await
+    }
+
+    async fn get_all_links(
+        &self,
+        base: &<V as VectorStore>::VectorRef,
+    ) -> Result<Vec<FurthestQueueV<V>>> {
+        self.inner.get_all_links(base).await
+    }
+
+    async fn get_inbound(
+        &self,
+        target: &<V as VectorStore>::VectorRef,
+        lc: usize,
+    ) -> Result<Vec<V::VectorRef>> {
+        self.inner.get_inbound(target, lc).await
+    }
+
+    async fn set_links(
+        &mut self,
+        base: V::VectorRef,
+        links: FurthestQueueV<V>,
+        lc: usize,
+    ) -> Result<()> {
+        let key = (base.clone(), lc);
+        self.cache.lock().unwrap().remove(&key);
+        self.written.insert(key);
+        let result = self.inner.set_links(base, links, lc).await;
+        if !self.inner.in_transaction() {
+            self.written.clear();
+        }
+        result
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        self.clear_cache();
+        self.written.clear();
+        self.inner.clear().await
+    }
+
+    async fn begin(&mut self) -> Result<()> {
+        self.inner.begin().await
+    }
+
+    async fn commit(&mut self) -> Result<()> {
+        let result = self.inner.commit().await;
+        self.invalidate_written();
+        result
+    }
+
+    async fn rollback(&mut self) -> Result<()> {
+        let result = self.inner.rollback().await;
+        self.invalidate_written();
+        result
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "db_dependent")]
+mod tests {
+    use super::*;
+    use crate::examples::lazy_memory_store::LazyMemoryStore;
+    use crate::graph_store::TestGraphPg;
+    use crate::hnsw_db::test_utils::insert_all;
+    use crate::hnsw_db::{FurthestQueue, HawkSearcher};
+
+    #[tokio::test]
+    async fn test_cached_graph_pg() {
+        let graph = TestGraphPg::<LazyMemoryStore>::new().await.unwrap();
+        let mut db = HawkSearcher::from_seed(LazyMemoryStore::new(), graph.owned(), 0);
+        let queries = (0..100_u64)
+            .map(|raw_query| db.vector_store.prepare_query_u64(raw_query * 7919))
+            .collect::<Vec<_>>();
+        insert_all(&mut db, &queries).await;
+
+        let cached = CachedGraphPg::new(graph.owned(), 1000);
+        let db = HawkSearcher::from_seed(db.vector_store, cached, 0);
+        let mut expected = vec![];
+        for query in queries.iter() {
+            expected.push(db.search(query, 5).await.unwrap());
+        }
+        let misses = db.graph_store.cache_misses();
+        assert!(misses > 0);
+        assert!(db.graph_store.cache_len() <= 1000);
+
+        // The same searches read the same links, now from the cache.
+        for (query, expected) in queries.iter().zip(expected.iter()) {
+            assert_eq!(db.search(query, 5).await.unwrap(), *expected);
+        }
+        assert_eq!(db.graph_store.cache_misses(), misses);
+        assert!(db.graph_store.cache_hits() > 0);
+
+        // A small cache evicts the least recently used lists, and returns the same links.
+        let small = CachedGraphPg::new(graph.owned(), 10);
+        let db = HawkSearcher::from_seed(db.vector_store, small, 0);
+        for (query, expected) in queries.iter().zip(expected.iter()) {
+            assert_eq!(db.search(query, 5).await.unwrap(), *expected);
+        }
+        assert_eq!(db.graph_store.cache_len(), 10);
+
+        graph.cleanup().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cached_graph_pg_writes() {
+        let graph = TestGraphPg::<LazyMemoryStore>::new().await.unwrap();
+        let mut vector_store = LazyMemoryStore::new();
+        let mut vectors = vec![];
+        for raw_query in 0..4 {
+            let query = vector_store.prepare_query_u64(raw_query);
+            vectors.push(vector_store.insert(&query).await);
+        }
+        let mut links = FurthestQueue::new();
+        for v in vectors[1..].iter() {
+            let d = vector_store.eval_distance(&vectors[0], v).await;
+            links.insert(&vector_store, *v, d).await;
+        }
+
+        let mut cached = CachedGraphPg::new(graph.owned(), 10);
+        let base = vectors[0];
+        assert!(cached.get_links(&base, 0).await.unwrap().is_empty());
+
+        // A write invalidates the cached list.
+        cached.set_links(base, links.clone(), 0).await.unwrap();
+        assert_eq!(cached.get_links(&base, 0).await.unwrap(), links);
+        let batch = cached.get_links_batch(&vectors, 0).await.unwrap();
+        assert_eq!(batch[0], links);
+        assert!(batch[1..].iter().all(|l| l.is_empty()));
+
+        // The writes of a transaction are seen after the commit, and not after a rollback.
+        let mut shorter = links.clone();
+        shorter.trim_to_k_nearest(1);
+        for commit in [false, true] {
+            cached.begin().await.unwrap();
+            cached.set_links(base, shorter.clone(), 0).await.unwrap();
+            assert_eq!(cached.get_links(&base, 0).await.unwrap(), links);
+            if commit {
+                cached.commit().await.unwrap();
+            } else {
+                cached.rollback().await.unwrap();
+            }
+            let expected = if commit { &shorter } else { &links };
+            assert_eq!(cached.get_links(&base, 0).await.unwrap(), *expected);
+        }
+
+        cached.clear().await.unwrap();
+        assert_eq!(cached.cache_len(), 0);
+        assert!(cached.get_links(&base, 0).await.unwrap().is_empty());
+
+        graph.cleanup().await.unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+use super::{EntryPoint, GraphMem, GraphStore};
+use crate::{
+    error::Result,
+    hnsw_db::{
+        coroutine::{Op, OpV},
+        FurthestQueueV,
+    },
+    VectorStore,
+};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// A `GraphMem` shared between clones, to search and insert concurrently from several tasks.
+///
+/// The graph is copied on write: the readers and `snapshot` share the current version, and a write only copies it if
+/// a snapshot still holds it. The lock is taken for each call to a `GraphStore` method. Outside of a transaction, each
+/// write is visible at once, so a search may see some of the links of an insertion in progress and not others, as it
+/// would in `GraphPg`. Insert with `HawkSearcher::insert_in_transaction` so that the searches see each insertion
+/// completely or not at all: the writes are buffered by `begin`, and applied together under the lock by `commit`.
+///
+/// An insertion reads and then writes the links of the neighbors, so concurrent insertions may lose each other's
+/// back-links. Insert from one task at a time.
+pub struct ConcurrentGraphMem<V: VectorStore> {
+    graph: Arc<RwLock<Arc<GraphMem<V>>>>,
+    /// The writes of the transaction started by `begin`, if any.
+    transaction: Option<Vec<OpV<V>>>,
+}
+
+/// A clone shares the graph, but not the transaction in progress, if any.
+impl<V: VectorStore> Clone for ConcurrentGraphMem<V> {
+    fn clone(&self) -> Self {
+        ConcurrentGraphMem {
+            graph: self.graph.clone(),
+            transaction: None,
+        }
+    }
+}
+
+impl<V: VectorStore> ConcurrentGraphMem<V> {
+    pub fn new() -> Self {
+        Self::from_graph(GraphMem::new())
+    }
+
+    pub fn from_graph(graph: GraphMem<V>) -> Self {
+        ConcurrentGraphMem {
+            graph: Arc::new(RwLock::new(Arc::new(graph))),
+            transaction: None,
+        }
+    }
+
+    /// The current version of the graph, which does not change with the following writes.
+    ///
+    /// This does not copy the graph: the next write does, if the snapshot is still alive.
+    pub async fn snapshot(&self) -> Arc<GraphMem<V>> {
+        self.graph.read().await.clone()
+    }
+
+    /// Apply a write, or buffer it in the transaction in progress.
+    async fn write(&mut self, op: OpV<V>) -> Result<()> {
+        match &mut self.transaction {
+            Some(transaction) => {
+                transaction.push(op);
+                Ok(())
+            }
+            None => {
+                let mut graph = self.graph.write().await;
+                apply(Arc::make_mut(&mut graph), op).await
+            }
+        }
+    }
+}
+
+async fn apply<V: VectorStore>(graph: &mut GraphMem<V>, op: OpV<V>) -> Result<()> {
+    match op {
+        Op::SetLinks { base, links, lc } => graph.set_links(base, links, lc).await,
+        Op::SetEntryPoint { entry_point } => graph.set_entry_point(entry_point).await,
+        op => unreachable!("not a write operation: {:?}", op),
+    }
+}
+
+impl<V: VectorStore> Default for ConcurrentGraphMem<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V: VectorStore> GraphStore<V> for ConcurrentGraphMem<V> {
+    async fn get_entry_point(&self) -> Result<Option<EntryPoint<V::VectorRef>>> {
+        self.graph.read().await.get_entry_point().await
+    }
+
+    async fn set_entry_point(&mut self, entry_point: EntryPoint<V::VectorRef>) -> Result<()> {
+        self.write(Op::SetEntryPoint { entry_point }).await
+    }
+
+    async fn get_links(
+        &self,
+        base: &<V as VectorStore>::VectorRef,
+        lc: usize,
+    ) -> Result<FurthestQueueV<V>> {
+        self.graph.read().await.get_links(base, lc).await
+    }
+
+    async fn get_links_batch(
+        &self,
+        bases: &[<V as VectorStore>::VectorRef],
+        lc: usize,
+    ) -> Result<Vec<FurthestQueueV<V>>> {
+        self.graph.read().await.get_links_batch(bases, lc).await
+    }
+
+    async fn with_links<R>(
+        &self,
+        base: &<V as VectorStore>::VectorRef,
+        lc: usize,
+        f: impl FnOnce(&FurthestQueueV<V>) -> R,
+    ) -> Result<R> {
+        self.graph.read().await.with_links(base, lc, f).await
+    }
+
+    async fn with_links_batch<R>(
+        &self,
+        bases: &[<V as VectorStore>::VectorRef],
+        lc: usize,
+        f: impl FnMut(&FurthestQueueV<V>) -> R,
+    ) -> Result<Vec<R>> {
+        self.graph.read().await.with_links_batch(bases, lc, f).await
+    }
+
+    async fn link_count(&self, lc: usize) -> Result<usize> {
+        self.graph.read().await.link_count(lc).await
+    }
+
+    async fn node_degree(&self, base: &<V as VectorStore>::VectorRef) -> Result<Vec<usize>> {
+        self.graph.read().await.node_degree(base).await
+    }
+
+    async fn get_all_links(
+        &self,
+        base: &<V as VectorStore>::VectorRef,
+    ) -> Result<Vec<FurthestQueueV<V>>> {
+        self.graph.read().await.get_all_links(base).await
+    }
+
+    async fn get_inbound(
+        &self,
+        target: &<V as VectorStore>::VectorRef,
+        lc: usize,
+    ) -> Result<Vec<V::VectorRef>> {
+        self.graph.read().await.get_inbound(target, lc).await
+    }
+
+    async fn set_links(
+        &mut self,
+        base: V::VectorRef,
+        links: FurthestQueueV<V>,
+        lc: usize,
+    ) -> Result<()> {
+        self.write(Op::SetLinks { base, links, lc }).await
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        let mut graph = self.graph.write().await;
+        *graph = Arc::new(GraphMem::new());
+        Ok(())
+    }
+
+    // Transactions.
+
+    /// Buffer the following writes until `commit` or `rollback`.
+    ///
+    /// The reads do not see the writes of the transaction before it is committed.
+    async fn begin(&mut self) -> Result<()> {
+        self.transaction = Some(vec![]);
+        Ok(())
+    }
+
+    /// Apply the writes of the transaction under one lock, so that the readers see all of them or none.
+    async fn commit(&mut self) -> Result<()> {
+        if let Some(transaction) = self.transaction.take() {
+            let mut graph = self.graph.write().await;
+            let graph = Arc::make_mut(&mut graph);
+            for op in transaction {
+                apply(graph, op).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn rollback(&mut self) -> Result<()> {
+        self.transaction = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::examples::lazy_memory_store::LazyMemoryStore;
+    use crate::hnsw_db::test_utils::prepare_queries;
+    use crate::hnsw_db::HawkSearcher;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_search_and_insert() {
+        let mut vector_store = LazyMemoryStore::new();
+        let queries = prepare_queries(&mut vector_store, 0..600);
+
+        let mut db = HawkSearcher::from_seed(vector_store, ConcurrentGraphMem::new(), 0);
+        for query in queries[..300].iter() {
+            db.insert_unique(query).await.unwrap();
+        }
+
+        // One writer inserts the other half, while readers search the first half.
+        let mut writer = db.clone();
+        let new_queries = queries[300..].to_vec();
+        let write = tokio::spawn(async move {
+            for query in new_queries.iter() {
+                let neighbors = writer.search_to_insert(query).await.unwrap();
+                let inserted = writer.vector_store.insert(query).await;
+                writer
+                    .insert_in_transaction(inserted, neighbors)
+                    .await
+                    .unwrap();
+            }
+        });
+        let reads = (0..4)
+            .map(|_| {
+                let reader = db.clone();
+                let old_queries = queries[..300].to_vec();
+                tokio::spawn(async move {
+                    for query in old_queries.iter() {
+                        assert!(reader.contains_match(query).await.unwrap());
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        write.await.unwrap();
+        for read in reads {
+            read.await.unwrap();
+        }
+
+        // The insertions are visible through the shared graph.
+        assert_eq!(db.graph_store.link_count(0).await.unwrap(), 600);
+        let snapshot = db.graph_store.snapshot().await;
+        assert_eq!(snapshot.link_count(0).await.unwrap(), 600);
+        for query in queries.iter() {
+            assert!(db.contains_match(query).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transaction_and_snapshot() {
+        let mut vector_store = LazyMemoryStore::new();
+        let queries = prepare_queries(&mut vector_store, 0..12);
+
+        let mut db = HawkSearcher::from_seed(vector_store, ConcurrentGraphMem::new(), 0);
+        for query in queries[..10].iter() {
+            db.insert_unique(query).await.unwrap();
+        }
+        let snapshot = db.graph_store.snapshot().await;
+        let reader = db.graph_store.clone();
+
+        // The writes of a transaction are not visible before the commit.
+        let neighbors = db.search_to_insert(&queries[10]).await.unwrap();
+        let inserted = db.vector_store.insert(&queries[10]).await;
+        let l = db.select_layer();
+        let plan = db.plan_insert(inserted, neighbors, l).await.unwrap();
+        db.graph_store.begin().await.unwrap();
+        db.apply_plan(plan).await.unwrap();
+        assert_eq!(reader.link_count(0).await.unwrap(), 10);
+        db.graph_store.commit().await.unwrap();
+        assert_eq!(reader.link_count(0).await.unwrap(), 11);
+
+        // The writes of a rolled back transaction are discarded.
+        let neighbors = db.search_to_insert(&queries[11]).await.unwrap();
+        let inserted = db.vector_store.insert(&queries[11]).await;
+        let plan = db.plan_insert(inserted, neighbors, 0).await.unwrap();
+        db.graph_store.begin().await.unwrap();
+        db.apply_plan(plan).await.unwrap();
+        db.graph_store.rollback().await.unwrap();
+        assert_eq!(reader.link_count(0).await.unwrap(), 11);
+
+        // The snapshot is the graph before the writes.
+        assert_eq!(snapshot.link_count(0).await.unwrap(), 10);
+    }
+}
@@ -1,14 +1,22 @@
-use super::{EntryPoint, GraphStore};
+use super::{debug_check_links, EntryPoint, GraphStore, MissingLayerWarning};
 use crate::{
-    hnsw_db::{FurthestQueue, FurthestQueueV},
-    VectorStore,
+    error::Result,
+    hnsw_db::{FurthestQueue, FurthestQueueV, HawkSearcher, Params},
+    HawkError, VectorStore,
 };
-use std::collections::HashMap;
+use aes_prng::AesRng;
+use bincode::Options;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::path::Path;
 
 #[derive(Default, Clone)]
 pub struct GraphMem<V: VectorStore> {
     entry_point: Option<EntryPoint<V::VectorRef>>,
     layers: Vec<Layer<V>>,
+    missing_layer_warning: MissingLayerWarning,
 }
 
 impl<V: VectorStore> GraphMem<V> {
@@ -16,6 +24,34 @@ impl<V: VectorStore> GraphMem<V> {
         GraphMem {
             entry_point: None,
             layers: vec![],
+            missing_layer_warning: Default::default(),
+        }
+    }
+
+    /// Build a graph from its entry point and its lists of links, as `(node, layer, links)`, e.g. loaded from a file
+    /// or a database.
+    ///
+    /// The layers are created up to the highest of the layers of the links and of the entry point, and a later list of
+    /// links for the same node and layer replaces an earlier one. Use `validate` to check a graph from an external
+    /// source.
+    pub fn from_links(
+        entry_point: Option<EntryPoint<V::VectorRef>>,
+        links: impl IntoIterator<Item = (V::VectorRef, usize, FurthestQueueV<V>)>,
+    ) -> Self {
+        let layer_count = entry_point.as_ref().map_or(0, |ep| ep.layer_count);
+        let mut layers = (0..layer_count).map(|_| Layer::new()).collect::<Vec<_>>();
+
+        for (node, lc, node_links) in links {
+            if lc >= layers.len() {
+                layers.resize_with(lc + 1, Layer::new);
+            }
+            layers[lc].set_links(node, node_links);
+        }
+
+        GraphMem {
+            entry_point,
+            layers,
+            missing_layer_warning: Default::default(),
         }
     }
 }
@@ -56,16 +92,424 @@ impl<V: VectorStore> GraphMem<V> {
         GraphMem::<V> {
             entry_point: new_entry,
             layers,
+            missing_layer_warning: Default::default(),
+        }
+    }
+}
+
+// Consistency checks, for graphs loaded from an external source.
+impl<V: VectorStore> GraphMem<V> {
+    /// Check the HNSW invariants, or report the nodes that break them: lists of links longer than the maximum degree of
+    /// their layer, and nodes present in a layer but missing from a lower layer.
+    ///
+    /// The violations convert into `HawkError::GraphInconsistent`, so `?` works in functions returning `Result`.
+    pub fn validate(&self, params: &Params) -> Result<(), Vec<Violation<V::VectorRef>>> {
+        let mut violations = vec![];
+
+        for (lc, layer) in self.layers.iter().enumerate() {
+            let max_degree = params.max_links(lc);
+
+            for (node, links) in layer.links.iter() {
+                if links.len() > max_degree {
+                    violations.push(Violation::OverDegree {
+                        node: node.clone(),
+                        layer: lc,
+                        degree: links.len(),
+                        max_degree,
+                    });
+                }
+
+                for lower in 0..lc {
+                    if !self.layers[lower].links.contains_key(node) {
+                        violations.push(Violation::MissingFromLowerLayer {
+                            node: node.clone(),
+                            layer: lc,
+                            missing_layer: lower,
+                        });
+                    }
+                }
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Trim the lists of links that exceed the maximum degree of their layer to their nearest neighbors.
+    ///
+    /// Nodes missing from lower layers are not repaired, because that requires a search.
+    pub fn repair(&mut self, params: &Params) {
+        for (lc, layer) in self.layers.iter_mut().enumerate() {
+            let max_degree = params.max_links(lc);
+
+            for links in layer.links.values_mut() {
+                links.trim_to_k_nearest(max_degree);
+            }
+        }
+    }
+}
+
+// Deletions.
+impl<V: VectorStore> GraphMem<V> {
+    /// Remove a node from the graph: its own links in all layers, and the links pointing to it.
+    ///
+    /// The entry point is not updated; call `repair_entry_point` after deleting it.
+    pub fn remove_node(&mut self, node: &V::VectorRef) {
+        for layer in self.layers.iter_mut() {
+            layer.links.remove(node);
+            for links in layer.links.values_mut() {
+                links.retain(|(n, _)| n != node);
+            }
         }
     }
+
+    /// The nodes of the graph, in any layer.
+    pub fn nodes(&self) -> HashSet<V::VectorRef> {
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.links.keys().cloned())
+            .collect()
+    }
+
+    /// Rename the nodes of the graph with `mapping`, and the distances of their links with `renumber_distance`, which
+    /// receives a distance and the new references of the base and neighbor of the link.
+    ///
+    /// The nodes missing from `mapping` are removed, with the links pointing to them, and the entry point is repaired
+    /// if it was removed.
+    pub fn renumber(
+        &mut self,
+        mapping: &HashMap<V::VectorRef, V::VectorRef>,
+        renumber_distance: impl Fn(&V::DistanceRef, &V::VectorRef, &V::VectorRef) -> V::DistanceRef,
+    ) where
+        V::VectorRef: Ord,
+    {
+        for layer in self.layers.iter_mut() {
+            layer.links = std::mem::take(&mut layer.links)
+                .into_iter()
+                .filter_map(|(node, links)| {
+                    let node = mapping.get(&node)?;
+                    let links = links
+                        .iter()
+                        .filter_map(|(n, d)| {
+                            let n = mapping.get(n)?;
+                            Some((n.clone(), renumber_distance(d, node, n)))
+                        })
+                        .collect();
+                    Some((node.clone(), FurthestQueue::from_ascending_vec(links)))
+                })
+                .collect();
+        }
+
+        self.entry_point = self.entry_point.take().and_then(|entry_point| {
+            Some(EntryPoint {
+                vector_ref: mapping.get(&entry_point.vector_ref)?.clone(),
+                layer_count: entry_point.layer_count,
+            })
+        });
+        self.repair_entry_point();
+    }
+
+    /// Make sure that the entry point is a node of the top populated layer, e.g. after deletions.
+    ///
+    /// If the current entry point is missing, a surviving node of the highest non-empty layer becomes the entry point,
+    /// with the layer count adjusted to that layer. This is the node with the most links in that layer, and the
+    /// smallest reference among those, so the choice does not depend on the order of the hash map. A graph without
+    /// nodes gets no entry point.
+    pub fn repair_entry_point(&mut self)
+    where
+        V::VectorRef: Ord,
+    {
+        let Some(top) = self
+            .layers
+            .iter()
+            .rposition(|layer| !layer.links.is_empty())
+        else {
+            self.entry_point = None;
+            return;
+        };
+        let top_links = &self.layers[top].links;
+
+        // A new entry point has no links yet in the layers above the others, so check the top populated layer.
+        if let Some(entry) = self.entry_point.as_ref() {
+            if top_links.contains_key(&entry.vector_ref) {
+                return;
+            }
+        }
+
+        let (vector_ref, _) = top_links
+            .iter()
+            .min_by(|(a, a_links), (b, b_links)| {
+                b_links.len().cmp(&a_links.len()).then_with(|| a.cmp(b))
+            })
+            .expect("top layer is not empty");
+        let vector_ref = vector_ref.clone();
+        self.entry_point = Some(EntryPoint {
+            vector_ref,
+            layer_count: top + 1,
+        });
+    }
+}
+
+// Visualization.
+type EdgeLabel<'a, V> = &'a dyn Fn(&<V as VectorStore>::DistanceRef) -> String;
+
+impl<V: VectorStore> GraphMem<V> {
+    /// Export the graph in the DOT format of Graphviz, with one cluster per layer and nodes labeled by their Debug form.
+    pub fn to_dot(&self) -> String {
+        self.dot(None)
+    }
+
+    /// Like `to_dot`, with the edges labeled by `label` applied to the distances.
+    pub fn to_dot_with_labels(&self, label: impl Fn(&V::DistanceRef) -> String) -> String {
+        self.dot(Some(&label))
+    }
+
+    fn dot(&self, label: Option<EdgeLabel<V>>) -> String {
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut out = String::from("digraph hnsw {\n");
+
+        for (lc, layer) in self.layers.iter().enumerate().rev() {
+            out += &format!("  subgraph cluster_layer_{} {{\n", lc);
+            out += &format!("    label={};\n", quote(&format!("layer {}", lc)));
+
+            // Sort for a stable output.
+            let mut nodes = layer
+                .links
+                .iter()
+                .map(|(node, links)| (format!("{:?}", node), links))
+                .collect::<Vec<_>>();
+            nodes.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            let id = |name: &str| quote(&format!("{}:{}", lc, name));
+            for (name, _) in nodes.iter() {
+                out += &format!("    {} [label={}];\n", id(name), quote(name));
+            }
+            for (name, links) in nodes.iter() {
+                for (neighbor, distance) in links.iter() {
+                    let neighbor = id(&format!("{:?}", neighbor));
+                    match label {
+                        Some(label) => {
+                            out += &format!(
+                                "    {} -> {} [label={}];\n",
+                                id(name),
+                                neighbor,
+                                quote(&label(distance))
+                            )
+                        }
+                        None => out += &format!("    {} -> {};\n", id(name), neighbor),
+                    }
+                }
+            }
+            out += "  }\n";
+        }
+
+        out += "}\n";
+        out
+    }
+}
+
+// Persistence.
+
+/// The encoding of a graph file written by `GraphMem::save`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Human-readable JSON.
+    Json,
+    /// Compact bincode, like `LinksEncoding::Bincode` of `GraphPg`.
+    Bincode,
+    /// MessagePack, compact and readable from other languages.
+    MessagePack,
+}
+
+impl Format {
+    const ALL: [Format; 3] = [Format::Json, Format::Bincode, Format::MessagePack];
+
+    /// The first line of a file in this format, which identifies the format when loading.
+    fn header(self) -> &'static [u8] {
+        match self {
+            Format::Json => b"hawk-pack graph json\n",
+            Format::Bincode => b"hawk-pack graph bincode\n",
+            Format::MessagePack => b"hawk-pack graph msgpack\n",
+        }
+    }
+}
+
+/// The content of a graph file, after the header.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+struct GraphFile<V: VectorStore> {
+    entry_point: Option<EntryPoint<V::VectorRef>>,
+    /// The links of each node, as `(node, layer, links)`.
+    links: Vec<(V::VectorRef, usize, FurthestQueueV<V>)>,
+}
+
+impl<V: VectorStore> GraphMem<V> {
+    /// Write the graph to a file in the given format. The file starts with a line naming the format, so `load` does not
+    /// need to be told.
+    pub async fn save(&self, path: impl AsRef<Path>, format: Format) -> Result<()> {
+        let file = GraphFile::<V> {
+            entry_point: self.entry_point.clone(),
+            links: self
+                .layers
+                .iter()
+                .enumerate()
+                .flat_map(|(lc, layer)| {
+                    layer
+                        .links
+                        .iter()
+                        .map(move |(node, links)| (node.clone(), lc, links.clone()))
+                })
+                .collect(),
+        };
+
+        let mut bytes = format.header().to_vec();
+        match format {
+            Format::Json => serde_json::to_writer(&mut bytes, &file)?,
+            Format::Bincode => bincode::DefaultOptions::new().serialize_into(&mut bytes, &file)?,
+            Format::MessagePack => rmp_serde::encode::write(&mut bytes, &file)?,
+        }
+        tokio::fs::write(path, bytes).await?;
+        Ok(())
+    }
+
+    /// Read a graph written by `save`, in either format. Use `validate` to check a graph from an external source.
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let bytes = tokio::fs::read(path).await?;
+        let format = Format::ALL
+            .into_iter()
+            .find(|format| bytes.starts_with(format.header()))
+            .ok_or_else(|| HawkError::IncompatibleIndex("unknown graph file format".to_string()))?;
+
+        let body = &bytes[format.header().len()..];
+        let file: GraphFile<V> = match format {
+            Format::Json => serde_json::from_slice(body)?,
+            Format::Bincode => bincode::DefaultOptions::new().deserialize(body)?,
+            Format::MessagePack => rmp_serde::from_slice(body)?,
+        };
+        Ok(Self::from_links(file.entry_point, file.links))
+    }
+}
+
+/// A broken invariant found by `GraphMem::validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation<VectorRef> {
+    /// The node has more links than allowed in this layer.
+    OverDegree {
+        node: VectorRef,
+        layer: usize,
+        degree: usize,
+        max_degree: usize,
+    },
+    /// The node has links in `layer`, but none in the lower layer `missing_layer`.
+    MissingFromLowerLayer {
+        node: VectorRef,
+        layer: usize,
+        missing_layer: usize,
+    },
+}
+
+impl<VectorRef: Debug> From<Vec<Violation<VectorRef>>> for HawkError {
+    fn from(violations: Vec<Violation<VectorRef>>) -> Self {
+        HawkError::GraphInconsistent(format!(
+            "{} violations of the HNSW invariants: {:?}",
+            violations.len(),
+            violations
+        ))
+    }
+}
+
+// Combine graphs built independently.
+impl<V: VectorStore> GraphMem<V> {
+    /// Merge a graph built over a disjoint set of vectors into this graph.
+    ///
+    /// The links of both graphs are kept, and each node of `other` is connected to its nearest neighbors in this graph,
+    /// found by a search bounded by `params.ef`, as during an insertion. The entry point becomes the one with the most layers.
+    ///
+    /// The nodes of this graph are not searched for in `other`, so the result is not as good as a graph built in one pass.
+    /// Exact recall requires a subsequent repair pass.
+    pub async fn merge(&mut self, other: GraphMem<V>, store: &V, params: &Params) -> Result<()>
+    where
+        V: VectorStore<QueryRef = <V as VectorStore>::VectorRef>,
+    {
+        // Search for the nodes of `other` in this graph, before the union. The RNG is not used without insertions.
+        let mut rng = AesRng::seed_from_u64(0);
+        let graph = std::mem::replace(self, GraphMem::new());
+        let searcher =
+            HawkSearcher::new_with_params(store.clone(), graph, params.clone(), &mut rng);
+
+        let mut cross_links = vec![];
+        for (lc, layer) in other.layers.iter().enumerate() {
+            for node in layer.links.keys() {
+                // Search each node once, from its top layer.
+                let is_top_layer = other
+                    .layers
+                    .get(lc + 1)
+                    .is_none_or(|upper| !upper.links.contains_key(node));
+                if !is_top_layer {
+                    continue;
+                }
+
+                let mut neighbors = searcher.search_to_insert(node).await?;
+                neighbors.truncate(lc + 1);
+                cross_links.push((node.clone(), neighbors));
+            }
+        }
+
+        *self = searcher.graph_store;
+
+        // Union of the links.
+        while self.layers.len() < other.layers.len() {
+            self.layers.push(Layer::new());
+        }
+        for (lc, layer) in other.layers.into_iter().enumerate() {
+            self.layers[lc].links.extend(layer.links);
+        }
+
+        // Connect the nodes of both graphs, in both directions.
+        for (node, neighbors) in cross_links {
+            for (lc, mut neighbors) in neighbors.into_iter().enumerate() {
+                neighbors.trim_to_k_nearest(params.M);
+                let max_links = params.max_links(lc);
+
+                let mut links = self.get_links(&node, lc).await?;
+                for (n, nq) in neighbors.iter() {
+                    links.insert(store, n.clone(), nq.clone()).await;
+
+                    let qn = store.eval_distance_rev(n, &node, nq).await;
+                    let mut n_links = self.get_links(n, lc).await?;
+                    n_links.insert(store, node.clone(), qn).await;
+                    n_links.trim_to_k_nearest(max_links);
+                    self.set_links(n.clone(), n_links, lc).await?;
+                }
+                links.trim_to_k_nearest(max_links);
+                self.set_links(node.clone(), links, lc).await?;
+            }
+        }
+
+        // Keep the entry point with the most layers.
+        if let Some(other_entry) = other.entry_point {
+            let replace = self
+                .entry_point
+                .as_ref()
+                .is_none_or(|entry| entry.layer_count < other_entry.layer_count);
+            if replace {
+                self.entry_point = Some(other_entry);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl<V: VectorStore> GraphStore<V> for GraphMem<V> {
-    async fn get_entry_point(&self) -> Option<EntryPoint<V::VectorRef>> {
-        self.entry_point.clone()
+    async fn get_entry_point(&self) -> Result<Option<EntryPoint<V::VectorRef>>> {
+        Ok(self.entry_point.clone())
     }
 
-    async fn set_entry_point(&mut self, entry_point: EntryPoint<V::VectorRef>) {
+    async fn set_entry_point(&mut self, entry_point: EntryPoint<V::VectorRef>) -> Result<()> {
         if let Some(previous) = self.entry_point.as_ref() {
             assert!(
                 previous.layer_count < entry_point.layer_count,
@@ -78,24 +522,113 @@ impl<V: VectorStore> GraphStore<V> for GraphMem<V> {
         }
 
         self.entry_point = Some(entry_point);
+        Ok(())
     }
 
     async fn get_links(
         &self,
         base: &<V as VectorStore>::VectorRef,
         lc: usize,
-    ) -> FurthestQueueV<V> {
-        let layer = &self.layers[lc];
+    ) -> Result<FurthestQueueV<V>> {
+        let Some(layer) = self.layers.get(lc) else {
+            // The entry point may claim more layers than are populated, e.g. after a partial load.
+            self.missing_layer_warning.warn(lc, self.layers.len());
+            return Ok(FurthestQueue::new());
+        };
         if let Some(links) = layer.get_links(base) {
-            links.clone()
+            Ok(links.clone())
         } else {
-            FurthestQueue::new()
+            Ok(FurthestQueue::new())
         }
     }
 
-    async fn set_links(&mut self, base: V::VectorRef, links: FurthestQueueV<V>, lc: usize) {
-        let layer = &mut self.layers[lc];
-        layer.set_links(base, links);
+    async fn with_links<R>(
+        &self,
+        base: &<V as VectorStore>::VectorRef,
+        lc: usize,
+        f: impl FnOnce(&FurthestQueueV<V>) -> R,
+    ) -> Result<R> {
+        match self.layers.get(lc).and_then(|layer| layer.get_links(base)) {
+            Some(links) => Ok(f(links)),
+            None => Ok(f(&self.get_links(base, lc).await?)),
+        }
+    }
+
+    async fn with_links_batch<R>(
+        &self,
+        bases: &[<V as VectorStore>::VectorRef],
+        lc: usize,
+        mut f: impl FnMut(&FurthestQueueV<V>) -> R,
+    ) -> Result<Vec<R>> {
+        let mut results = Vec::with_capacity(bases.len());
+        for base in bases {
+            results.push(self.with_links(base, lc, &mut f).await?);
+        }
+        Ok(results)
+    }
+
+    async fn link_count(&self, lc: usize) -> Result<usize> {
+        Ok(self.layers.get(lc).map_or(0, |layer| layer.links.len()))
+    }
+
+    async fn node_degree(&self, base: &<V as VectorStore>::VectorRef) -> Result<Vec<usize>> {
+        Ok(self
+            .layers
+            .iter()
+            .map(|layer| layer.get_links(base).map_or(0, |links| links.len()))
+            .collect())
+    }
+
+    async fn get_all_links(
+        &self,
+        base: &<V as VectorStore>::VectorRef,
+    ) -> Result<Vec<FurthestQueueV<V>>> {
+        Ok(self
+            .layers
+            .iter()
+            .map(|layer| {
+                layer
+                    .get_links(base)
+                    .cloned()
+                    .unwrap_or_else(FurthestQueue::new)
+            })
+            .collect())
+    }
+
+    async fn get_inbound(
+        &self,
+        target: &<V as VectorStore>::VectorRef,
+        lc: usize,
+    ) -> Result<Vec<V::VectorRef>> {
+        Ok(self.layers.get(lc).map_or(vec![], |layer| {
+            layer
+                .links
+                .iter()
+                .filter(|(_, links)| links.iter().any(|(e, _)| e == target))
+                .map(|(base, _)| base.clone())
+                .collect()
+        }))
+    }
+
+    async fn set_links(
+        &mut self,
+        base: V::VectorRef,
+        links: FurthestQueueV<V>,
+        lc: usize,
+    ) -> Result<()> {
+        debug_check_links::<V>(&base, &links, lc);
+        // The links of a new top layer are written before the entry point that claims it.
+        while lc >= self.layers.len() {
+            self.layers.push(Layer::new());
+        }
+        self.layers[lc].set_links(base, links);
+        Ok(())
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        self.entry_point = None;
+        self.layers.clear();
+        Ok(())
     }
 }
 
@@ -108,7 +641,7 @@ pub struct Layer<V: VectorStore> {
 impl<V: VectorStore> Layer<V> {
     #[cfg(feature = "bench")]
     pub fn nodes(&self) -> Vec<V::VectorRef> {
-        self.links.keys().map(|k| k.clone()).collect()
+        self.links.keys().cloned().collect()
     }
 
     fn new() -> Self {
@@ -125,3 +658,464 @@ impl<V: VectorStore> Layer<V> {
         self.links.insert(from, links);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::examples::lazy_memory_store::LazyMemoryStore;
+    use crate::hnsw_db::test_utils::{insert_all, prepare_queries};
+    use std::collections::HashSet;
+
+    #[tokio::test]
+    async fn test_validate_and_repair() {
+        let mut vector_store = LazyMemoryStore::new();
+        let mut graph = GraphMem::<LazyMemoryStore>::new();
+        let params = Params {
+            Mmax: 2,
+            Mmax0: 3,
+            ..Params::default()
+        };
+
+        let vectors = {
+            let mut v = vec![];
+            for raw_query in 0..6 {
+                let q = vector_store.prepare_query_u64(raw_query);
+                v.push(vector_store.insert(&q).await);
+            }
+            v
+        };
+
+        graph
+            .set_entry_point(EntryPoint {
+                vector_ref: vectors[0],
+                layer_count: 2,
+            })
+            .await
+            .unwrap();
+
+        // A consistent graph.
+        let mut links = FurthestQueue::new();
+        for v in vectors[1..4].iter() {
+            let d = vector_store.eval_distance(&vectors[0], v).await;
+            links.insert(&vector_store, *v, d).await;
+        }
+        graph.set_links(vectors[0], links.clone(), 0).await.unwrap();
+        graph
+            .set_links(vectors[0], FurthestQueue::new(), 1)
+            .await
+            .unwrap();
+        assert_eq!(graph.validate(&params), Ok(()));
+
+        // Inject an over-degree node in layer 1.
+        graph.set_links(vectors[0], links.clone(), 1).await.unwrap();
+        // Inject a node in layer 1 that is missing from layer 0.
+        graph
+            .set_links(vectors[5], FurthestQueue::new(), 1)
+            .await
+            .unwrap();
+
+        let mut violations = graph.validate(&params).unwrap_err();
+        violations.sort_by_key(|v| matches!(v, Violation::MissingFromLowerLayer { .. }));
+        assert_eq!(
+            violations,
+            vec![
+                Violation::OverDegree {
+                    node: vectors[0],
+                    layer: 1,
+                    degree: 3,
+                    max_degree: 2,
+                },
+                Violation::MissingFromLowerLayer {
+                    node: vectors[5],
+                    layer: 1,
+                    missing_layer: 0,
+                },
+            ]
+        );
+
+        // Repair trims to the nearest links only.
+        graph.repair(&params);
+        let repaired = graph.get_links(&vectors[0], 1).await.unwrap();
+        assert_eq!(*repaired, links[..2]);
+        assert_eq!(graph.validate(&params).unwrap_err().len(), 1);
+
+        // The violations convert into an error of the crate.
+        let check = || -> Result<()> {
+            graph.validate(&params)?;
+            Ok(())
+        };
+        assert!(matches!(check(), Err(HawkError::GraphInconsistent(_))));
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    #[should_panic(expected = "link to itself")]
+    async fn test_set_links_to_itself() {
+        let mut vector_store = LazyMemoryStore::new();
+        let mut graph = GraphMem::<LazyMemoryStore>::new();
+        let query = vector_store.prepare_query_u64(0);
+        let vector = vector_store.insert(&query).await;
+        let distance = vector_store.eval_distance(&query, &vector).await;
+
+        let links = FurthestQueue::from_ascending_vec(vec![(vector, distance)]);
+        graph.set_links(vector, links, 0).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_entry_point_above_populated_layers() {
+        let mut vector_store = LazyMemoryStore::new();
+        let queries = prepare_queries(&mut vector_store, 0..10);
+
+        // Build a consistent single-layer graph.
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let mut db = HawkSearcher::new(vector_store, GraphMem::new(), &mut rng);
+        insert_all(&mut db, &queries).await;
+
+        // Keep only layer 0, but claim 4 layers in the entry point.
+        let graph = &mut db.graph_store;
+        graph.layers.truncate(1);
+        graph.entry_point = Some(EntryPoint {
+            vector_ref: queries[0],
+            layer_count: 4,
+        });
+
+        for query in queries.iter() {
+            let neighbors = db.search_to_insert(query).await.unwrap();
+            assert_eq!(neighbors.len(), 4);
+            assert!(db.is_match(&neighbors).await);
+        }
+        assert!(db.graph_store.missing_layer_warning.warned());
+    }
+
+    #[tokio::test]
+    async fn test_repair_entry_point() {
+        let vector_store = LazyMemoryStore::new();
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let mut db = HawkSearcher::new(vector_store, GraphMem::new(), &mut rng);
+
+        let queries = prepare_queries(&mut db.vector_store, 0..100);
+        insert_all(&mut db, &queries).await;
+
+        // An intact entry point is kept.
+        let entry = db.graph_store.get_entry_point().await.unwrap().unwrap();
+        db.graph_store.repair_entry_point();
+        assert_eq!(
+            db.graph_store.get_entry_point().await.unwrap(),
+            Some(entry.clone())
+        );
+
+        // Delete the entry point.
+        db.graph_store.remove_node(&entry.vector_ref);
+        db.graph_store.repair_entry_point();
+
+        let new_entry = db.graph_store.get_entry_point().await.unwrap().unwrap();
+        assert_ne!(new_entry.vector_ref, entry.vector_ref);
+        assert!(new_entry.layer_count <= entry.layer_count);
+        let top_links = db
+            .graph_store
+            .get_links(&new_entry.vector_ref, new_entry.layer_count - 1)
+            .await
+            .unwrap();
+        assert!(db.graph_store.layers[new_entry.layer_count - 1]
+            .links
+            .contains_key(&new_entry.vector_ref));
+        assert!(top_links.iter().all(|(n, _)| *n != entry.vector_ref));
+
+        // The replacement is the node with the most links in the top layer, then the smallest.
+        let top_layer = &db.graph_store.layers[new_entry.layer_count - 1].links;
+        let expected = top_layer
+            .iter()
+            .map(|(n, links)| (std::cmp::Reverse(links.len()), n))
+            .min()
+            .unwrap()
+            .1;
+        assert_eq!(new_entry.vector_ref, *expected);
+
+        // The surviving vectors are still found.
+        for query in queries.iter().filter(|q| **q != entry.vector_ref) {
+            let neighbors = db.search_to_insert(query).await.unwrap();
+            assert!(db.is_match(&neighbors).await);
+        }
+
+        // Without nodes, there is no entry point.
+        for query in queries.iter() {
+            db.graph_store.remove_node(query);
+        }
+        db.graph_store.repair_entry_point();
+        assert_eq!(db.graph_store.get_entry_point().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_from_links() {
+        let mut vector_store = LazyMemoryStore::new();
+        let mut vectors = vec![];
+        for raw_query in [0b0000, 0b0001, 0b0011, 0b0111, 0b1111] {
+            let query = vector_store.prepare_query_u64(raw_query);
+            vectors.push(vector_store.insert(&query).await);
+        }
+
+        // A path 0 - 1 - 2 - 3 - 4 on layer 0, and 0 - 4 on layer 1.
+        let mut links = vec![];
+        for (i, v) in vectors.iter().enumerate() {
+            let neighbors = [i.checked_sub(1), Some(i + 1).filter(|&j| j < vectors.len())];
+            let mut queue = FurthestQueue::new();
+            for j in neighbors.into_iter().flatten() {
+                let d = vector_store.eval_distance(v, &vectors[j]).await;
+                queue.insert(&vector_store, vectors[j], d).await;
+            }
+            links.push((*v, 0, queue));
+        }
+        for (a, b) in [(0, 4), (4, 0)] {
+            let d = vector_store.eval_distance(&vectors[a], &vectors[b]).await;
+            links.push((
+                vectors[a],
+                1,
+                FurthestQueue::from_ascending_vec(vec![(vectors[b], d)]),
+            ));
+        }
+        let entry_point = EntryPoint {
+            vector_ref: vectors[0],
+            layer_count: 2,
+        };
+
+        let graph = GraphMem::from_links(Some(entry_point.clone()), links.clone());
+        assert_eq!(graph.layers.len(), 2);
+        assert_eq!(graph.validate(&Params::default()), Ok(()));
+        assert_eq!(graph.get_entry_point().await.unwrap(), Some(entry_point));
+        for (node, lc, node_links) in links {
+            assert_eq!(graph.get_links(&node, lc).await.unwrap(), node_links);
+        }
+
+        // Every vector is found along the path.
+        let db = HawkSearcher::from_seed(vector_store.clone(), graph, 0);
+        for v in vectors.iter() {
+            let results = db.search(v, 1).await.unwrap();
+            assert_eq!(results[0].0, *v);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_node_degree() {
+        let vector_store = LazyMemoryStore::new();
+        let params = Params {
+            M: 4,
+            Mmax: 4,
+            Mmax0: 8,
+            ..Params::default()
+        };
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let mut db = HawkSearcher::new_with_params(vector_store, GraphMem::new(), params, &mut rng);
+
+        let queries = prepare_queries(&mut db.vector_store, 0..100);
+        insert_all(&mut db, &queries).await;
+
+        let layer_count = db.graph_store.layers.len();
+        for query in queries.iter() {
+            let degrees = db.graph_store.node_degree(query).await.unwrap();
+            let all_links = db.graph_store.get_all_links(query).await.unwrap();
+            assert_eq!(degrees.len(), layer_count);
+            assert_eq!(all_links.len(), layer_count);
+            assert!(degrees[0] > 0);
+            for (lc, degree) in degrees.into_iter().enumerate() {
+                assert!(degree <= db.params().max_links(lc));
+                let links = db.graph_store.get_links(query, lc).await.unwrap();
+                assert_eq!(degree, links.len());
+                assert_eq!(all_links[lc], links);
+                let borrowed = db.graph_store.with_links(query, lc, |l| l.clone());
+                assert_eq!(borrowed.await.unwrap(), links);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_inbound() {
+        let vector_store = LazyMemoryStore::new();
+        let params = Params {
+            M: 4,
+            Mmax: 4,
+            Mmax0: 8,
+            ..Params::default()
+        };
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let mut db = HawkSearcher::new_with_params(vector_store, GraphMem::new(), params, &mut rng);
+
+        let queries = prepare_queries(&mut db.vector_store, 0..100);
+        insert_all(&mut db, &queries).await;
+
+        // u is inbound to v if and only if v is in the links of u.
+        for lc in 0..db.graph_store.layers.len() {
+            let mut outbound = HashSet::new();
+            for query in queries.iter() {
+                for (v, _) in db.graph_store.get_links(query, lc).await.unwrap().iter() {
+                    outbound.insert((*query, *v));
+                }
+            }
+
+            let mut inbound = HashSet::new();
+            for query in queries.iter() {
+                for u in db.graph_store.get_inbound(query, lc).await.unwrap() {
+                    assert!(inbound.insert((u, *query)), "duplicate inbound link");
+                }
+            }
+            assert_eq!(inbound, outbound);
+        }
+        assert!(db
+            .graph_store
+            .get_inbound(&queries[0], db.graph_store.layers.len())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_to_dot() {
+        let vector_store = LazyMemoryStore::new();
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let mut db = HawkSearcher::new(vector_store, GraphMem::new(), &mut rng);
+        for raw_query in 0..10 {
+            let query = db.vector_store.prepare_query_u64(raw_query);
+            db.insert_unique(&query).await.unwrap();
+        }
+        let graph = &db.graph_store;
+
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph hnsw {"));
+
+        let node_count = graph.layers.iter().map(|l| l.links.len()).sum::<usize>();
+        let edge_count = graph
+            .layers
+            .iter()
+            .flat_map(|l| l.links.values())
+            .map(|links| links.len())
+            .sum::<usize>();
+        assert_eq!(dot.matches("[label=\"PointId(").count(), node_count);
+        assert_eq!(dot.matches(" -> ").count(), edge_count);
+        assert_eq!(dot.matches("subgraph").count(), graph.layers.len());
+
+        let labeled = graph.to_dot_with_labels(|(a, b)| format!("{:?}-{:?}", a, b));
+        assert_eq!(labeled.matches(")-PointId(").count(), edge_count);
+    }
+
+    #[tokio::test]
+    async fn test_save_load() {
+        let vector_store = LazyMemoryStore::new();
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let mut db = HawkSearcher::new(vector_store, GraphMem::new(), &mut rng);
+        for raw_query in 0..200 {
+            let query = db.vector_store.prepare_query_u64(raw_query * 7919);
+            db.insert_unique(&query).await.unwrap();
+        }
+        let graph = &db.graph_store;
+        let label = |(a, b): &(_, _)| format!("{:?}-{:?}", a, b);
+
+        let dir = std::env::temp_dir();
+        let mut sizes = vec![];
+        for format in Format::ALL {
+            let path = dir.join(format!(
+                "hawk-pack-test-save-load-{}-{:?}",
+                std::process::id(),
+                format
+            ));
+            graph.save(&path, format).await.unwrap();
+            let bytes = tokio::fs::read(&path).await.unwrap();
+            assert!(bytes.starts_with(format.header()));
+            sizes.push(bytes.len());
+
+            let loaded = GraphMem::<LazyMemoryStore>::load(&path).await.unwrap();
+            tokio::fs::remove_file(&path).await.unwrap();
+            assert_eq!(loaded.entry_point, graph.entry_point);
+            assert_eq!(loaded.layers.len(), graph.layers.len());
+            assert_eq!(
+                loaded.to_dot_with_labels(label),
+                graph.to_dot_with_labels(label)
+            );
+        }
+        // The binary files are smaller.
+        assert!(sizes[1] < sizes[0], "sizes: {:?}", sizes);
+        assert!(sizes[2] < sizes[0], "sizes: {:?}", sizes);
+
+        // A file of another format is rejected.
+        let path = dir.join(format!("hawk-pack-test-save-load-{}", std::process::id()));
+        tokio::fs::write(&path, b"{}").await.unwrap();
+        let err = GraphMem::<LazyMemoryStore>::load(&path)
+            .await
+            .err()
+            .unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert!(matches!(err, HawkError::IncompatibleIndex(_)));
+
+        let err = GraphMem::<LazyMemoryStore>::load(&path)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, HawkError::Io(_)));
+    }
+
+    #[tokio::test]
+    async fn test_clear() {
+        let mut db = HawkSearcher::from_seed(LazyMemoryStore::new(), GraphMem::new(), 0);
+        let queries = prepare_queries(&mut db.vector_store, 0..100);
+        insert_all(&mut db, &queries).await;
+
+        db.graph_store.clear().await.unwrap();
+        assert_eq!(db.graph_store.get_entry_point().await.unwrap(), None);
+        assert_eq!(db.graph_store.link_count(0).await.unwrap(), 0);
+        let neighbors = db.search_to_insert(&queries[0]).await.unwrap();
+        assert!(neighbors.iter().all(|layer| layer.is_empty()));
+        assert!(!db.contains_match(&queries[0]).await.unwrap());
+
+        // The graph can be rebuilt.
+        db.insert_unique(&queries[0]).await.unwrap();
+        assert!(db.contains_match(&queries[0]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        let mut vector_store = LazyMemoryStore::new();
+        let queries = prepare_queries(&mut vector_store, 0..1000);
+
+        // Build two graphs over disjoint halves.
+        let mut graphs = vec![];
+        for (seed, half) in queries.chunks(500).enumerate() {
+            let mut rng = AesRng::seed_from_u64(seed as u64);
+            let mut db = HawkSearcher::new(vector_store.clone(), GraphMem::new(), &mut rng);
+            insert_all(&mut db, half).await;
+            vector_store = db.vector_store;
+            graphs.push(db.graph_store);
+        }
+        let other = graphs.pop().unwrap();
+        let mut graph = graphs.pop().unwrap();
+        let layer_counts = [&graph, &other].map(|g| g.entry_point.as_ref().unwrap().layer_count);
+
+        graph
+            .merge(other, &vector_store, &Params::default())
+            .await
+            .unwrap();
+
+        let entry_point = graph.get_entry_point().await.unwrap().unwrap();
+        assert_eq!(
+            entry_point.layer_count,
+            layer_counts[0].max(layer_counts[1])
+        );
+
+        // All nodes are reachable from the entry point in layer 0.
+        let mut reached = HashSet::from([entry_point.vector_ref]);
+        let mut to_visit = vec![entry_point.vector_ref];
+        while let Some(node) = to_visit.pop() {
+            for (neighbor, _) in graph.get_links(&node, 0).await.unwrap().iter() {
+                if reached.insert(*neighbor) {
+                    to_visit.push(*neighbor);
+                }
+            }
+        }
+        assert_eq!(reached.len(), queries.len());
+
+        // All vectors are found by a search.
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let db = HawkSearcher::new(vector_store, graph, &mut rng);
+        for query in queries.iter() {
+            let neighbors = db.search_to_insert(query).await.unwrap();
+            assert!(db.is_match(&neighbors).await);
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use super::{debug_check_links, EntryPoint, GraphStore, MissingLayerWarning};
+use crate::{
+    error::Result,
+    hnsw_db::{FurthestQueue, FurthestQueueV},
+    VectorStore,
+};
+
+/// An in-memory graph for stores whose vectors are identified by contiguous integers.
+///
+/// Compared to `GraphMem`, the links are stored in dense vectors indexed by layer and by vector ID, instead of hash
+/// maps. The memory use is proportional to the largest vector ID.
+#[derive(Default, Clone)]
+pub struct GraphVec<V: VectorStore> {
+    entry_point: Option<EntryPoint<V::VectorRef>>,
+    /// The links of each vector, indexed by layer then by vector ID. None if the links were never set.
+    layers: Vec<Vec<Option<FurthestQueueV<V>>>>,
+    missing_layer_warning: MissingLayerWarning,
+}
+
+impl<V: VectorStore> GraphVec<V> {
+    pub fn new() -> Self {
+        GraphVec {
+            entry_point: None,
+            layers: vec![],
+            missing_layer_warning: Default::default(),
+        }
+    }
+}
+
+impl<V> GraphStore<V> for GraphVec<V>
+where
+    V: VectorStore,
+    V::VectorRef: Into<usize>,
+{
+    async fn get_entry_point(&self) -> Result<Option<EntryPoint<V::VectorRef>>> {
+        Ok(self.entry_point.clone())
+    }
+
+    async fn set_entry_point(&mut self, entry_point: EntryPoint<V::VectorRef>) -> Result<()> {
+        if let Some(previous) = self.entry_point.as_ref() {
+            assert!(
+                previous.layer_count < entry_point.layer_count,
+                "A new entry point should be on a higher layer than before."
+            );
+        }
+
+        while entry_point.layer_count > self.layers.len() {
+            self.layers.push(vec![]);
+        }
+
+        self.entry_point = Some(entry_point);
+        Ok(())
+    }
+
+    async fn get_links(
+        &self,
+        base: &<V as VectorStore>::VectorRef,
+        lc: usize,
+    ) -> Result<FurthestQueueV<V>> {
+        let Some(layer) = self.layers.get(lc) else {
+            self.missing_layer_warning.warn(lc, self.layers.len());
+            return Ok(FurthestQueue::new());
+        };
+        let id: usize = base.clone().into();
+        match layer.get(id) {
+            Some(Some(links)) => Ok(links.clone()),
+            _ => Ok(FurthestQueue::new()),
+        }
+    }
+
+    async fn with_links<R>(
+        &self,
+        base: &<V as VectorStore>::VectorRef,
+        lc: usize,
+        f: impl FnOnce(&FurthestQueueV<V>) -> R,
+    ) -> Result<R> {
+        let id: usize = base.clone().into();
+        match self.layers.get(lc).and_then(|layer| layer.get(id)) {
+            Some(Some(links)) => Ok(f(links)),
+            _ => Ok(f(&self.get_links(base, lc).await?)),
+        }
+    }
+
+    async fn with_links_batch<R>(
+        &self,
+        bases: &[<V as VectorStore>::VectorRef],
+        lc: usize,
+        mut f: impl FnMut(&FurthestQueueV<V>) -> R,
+    ) -> Result<Vec<R>> {
+        let mut results = Vec::with_capacity(bases.len());
+        for base in bases {
+            results.push(self.with_links(base, lc, &mut f).await?);
+        }
+        Ok(results)
+    }
+
+    async fn link_count(&self, lc: usize) -> Result<usize> {
+        Ok(self.layers.get(lc).map_or(0, |layer| {
+            layer.iter().filter(|links| links.is_some()).count()
+        }))
+    }
+
+    async fn set_links(
+        &mut self,
+        base: V::VectorRef,
+        links: FurthestQueueV<V>,
+        lc: usize,
+    ) -> Result<()> {
+        debug_check_links::<V>(&base, &links, lc);
+        // The links of a new top layer are written before the entry point that claims it.
+        while lc >= self.layers.len() {
+            self.layers.push(vec![]);
+        }
+        let layer = &mut self.layers[lc];
+        let id: usize = base.into();
+        if id >= layer.len() {
+            layer.resize_with(id + 1, || None);
+        }
+        layer[id] = Some(links);
+        Ok(())
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        self.entry_point = None;
+        self.layers.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::examples::lazy_memory_store::LazyMemoryStore;
+    use crate::graph_store::GraphMem;
+    use crate::hnsw_db::test_utils::{insert_all, prepare_queries};
+    use crate::hnsw_db::HawkSearcher;
+    use crate::HawkError;
+
+    #[tokio::test]
+    async fn test_same_as_graph_mem() {
+        let mut vector_store = LazyMemoryStore::new();
+        let queries = prepare_queries(&mut vector_store, 0..200);
+
+        let mut mem = HawkSearcher::from_seed(vector_store.clone(), GraphMem::new(), 0);
+        let mut vec = HawkSearcher::from_seed(vector_store, GraphVec::new(), 0);
+        insert_all(&mut mem, &queries).await;
+        insert_all(&mut vec, &queries).await;
+
+        let entry_point = mem.graph_store.get_entry_point().await.unwrap().unwrap();
+        assert_eq!(
+            vec.graph_store.get_entry_point().await.unwrap(),
+            Some(entry_point.clone())
+        );
+        for lc in 0..entry_point.layer_count {
+            assert_eq!(
+                vec.graph_store.link_count(lc).await.unwrap(),
+                mem.graph_store.link_count(lc).await.unwrap()
+            );
+            for query in queries.iter() {
+                assert_eq!(
+                    vec.graph_store.get_links(query, lc).await.unwrap(),
+                    mem.graph_store.get_links(query, lc).await.unwrap()
+                );
+            }
+        }
+
+        for query in queries.iter() {
+            let neighbors = vec.search_to_insert(query).await.unwrap();
+            assert!(vec.is_match(&neighbors).await);
+        }
+
+        // The sources are not stored, only their IDs.
+        assert!(matches!(
+            vec.graph_store.get_inbound(&queries[0], 0).await,
+            Err(HawkError::Unsupported(_))
+        ));
+    }
+}
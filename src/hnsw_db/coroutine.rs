@@ -1,10 +1,10 @@
 use crate::{
+    error::Result,
     graph_store::EntryPoint,
     hnsw_db::{FurthestQueue, HawkSearcher},
     GraphStore, Ref, VectorStore,
 };
-use aes_prng::AesRng;
-use std::fmt::Debug;
+use std::{collections::HashSet, fmt::Debug};
 use tokio::sync::{mpsc, oneshot};
 use tokio_stream::wrappers::ReceiverStream;
 
@@ -20,7 +20,10 @@ use tokio_stream::wrappers::ReceiverStream;
 /// returns `None`. Each call to `next` will return an `Op` that represents the
 /// operation that the search routine is waiting for. The caller is responsible for
 /// executing the operation and sending the result back to the routine.
-pub fn search_to_insert_stream<Q, V, D>(query: Q) -> HawkStream<Q, V, D>
+///
+/// With `k: None`, the result has the neighbors of the query in each layer, to insert it. With `k: Some(k)`, this is
+/// a k-NN search like `HawkSearcher::search`: the result has only layer 0, trimmed to the k nearest.
+pub fn search_to_insert_stream<Q, V, D>(query: Q, k: Option<usize>) -> HawkStream<Q, V, D>
 where
     Q: Ref + Send + 'static,
     V: Ref + Send + 'static,
@@ -28,20 +31,70 @@ where
 {
     let (tx, rx) = mpsc::channel(1);
     tokio::spawn(async move {
-        let mut rng = AesRng::from_random_seed();
-        let hawk = HawkSearcher::new(
+        // Searches do not use the RNG, so a fixed seed keeps the routine deterministic.
+        let hawk = HawkSearcher::from_seed(
             OpsCollector { ops: tx.clone() },
             OpsCollector { ops: tx.clone() },
-            &mut rng,
+            0,
         );
-        let result = hawk.search_to_insert(&query).await;
-        tx.send(Op::SearchResult { query, result }).await.unwrap();
+        let result = match k {
+            None => hawk.search_to_insert(&query).await.unwrap(),
+            Some(k) => {
+                let nearest = hawk.search(&query, k).await.unwrap();
+                if nearest.is_empty() {
+                    vec![] // Empty database, as for an insertion.
+                } else {
+                    vec![FurthestQueue::from_ascending_vec(nearest)]
+                }
+            }
+        };
+        tx.send(Op::SearchResult {
+            query,
+            result,
+            visited: None,
+        })
+        .await
+        .unwrap();
+    });
+    ReceiverStream::new(rx)
+}
+
+/// Like `search_to_insert_stream` with `k: None`, and the result also has the set of vectors visited in each layer.
+///
+/// This tells a coordinator which vectors the search needed, e.g. to decide what to cache.
+pub fn search_to_insert_stream_with_visited<Q, V, D>(query: Q) -> HawkStream<Q, V, D>
+where
+    Q: Ref + Send + 'static,
+    V: Ref + Send + 'static,
+    D: Ref + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let hawk = HawkSearcher::from_seed(
+            OpsCollector { ops: tx.clone() },
+            OpsCollector { ops: tx.clone() },
+            0,
+        );
+        let (result, visited) = hawk.search_to_insert_with_visited(&query).await.unwrap();
+        tx.send(Op::SearchResult {
+            query,
+            result,
+            visited: Some(visited),
+        })
+        .await
+        .unwrap();
     });
     ReceiverStream::new(rx)
 }
 
 pub type HawkStream<Q, V, D> = ReceiverStream<Op<Q, V, D>>;
 
+pub type OpV<V> = Op<
+    <V as VectorStore>::QueryRef,
+    <V as VectorStore>::VectorRef,
+    <V as VectorStore>::DistanceRef,
+>;
+
 #[derive(Debug)]
 pub enum Op<Query, Vector, Distance> {
     // VectorStore operations.
@@ -68,6 +121,11 @@ pub enum Op<Query, Vector, Distance> {
         lc: usize,
         reply: oneshot::Sender<FurthestQueue<Vector, Distance>>,
     },
+    GetLinksBatch {
+        bases: Vec<Vector>,
+        lc: usize,
+        reply: oneshot::Sender<Vec<FurthestQueue<Vector, Distance>>>,
+    },
     SetLinks {
         base: Vector,
         links: FurthestQueue<Vector, Distance>,
@@ -78,6 +136,8 @@ pub enum Op<Query, Vector, Distance> {
     SearchResult {
         query: Query,
         result: Vec<FurthestQueue<Vector, Distance>>,
+        /// The vectors visited in each layer (bottom=0), if requested with `search_to_insert_stream_with_visited`.
+        visited: Option<Vec<HashSet<Vector>>>,
     },
 }
 
@@ -100,7 +160,7 @@ impl<Q: Ref, V: Ref, D: Ref> VectorStore for OpsCollector<Q, V, D> {
         query: &Self::QueryRef,
         vector: &Self::VectorRef,
     ) -> Self::DistanceRef {
-        self.eval_distance_batch(query, &[vector.clone()])
+        self.eval_distance_batch(query, std::slice::from_ref(vector))
             .await
             .pop()
             .unwrap()
@@ -128,7 +188,7 @@ impl<Q: Ref, V: Ref, D: Ref> VectorStore for OpsCollector<Q, V, D> {
         distance1: &Self::DistanceRef,
         distance2: &Self::DistanceRef,
     ) -> bool {
-        self.less_than_batch(distance1, &[distance2.clone()])
+        self.less_than_batch(distance1, std::slice::from_ref(distance2))
             .await
             .pop()
             .unwrap()
@@ -157,21 +217,22 @@ impl<Q: Ref, V: Ref, D: Ref> VectorStore for OpsCollector<Q, V, D> {
 }
 
 impl<Q: Ref, V: Ref, D: Ref> GraphStore<OpsCollector<Q, V, D>> for OpsCollector<Q, V, D> {
-    async fn get_entry_point(&self) -> Option<EntryPoint<V>> {
+    async fn get_entry_point(&self) -> Result<Option<EntryPoint<V>>> {
         let (reply, get_reply) = oneshot::channel();
 
         let op = Op::GetEntryPoint { reply };
 
         self.ops.send(op).await.unwrap();
-        get_reply.await.unwrap()
+        Ok(get_reply.await.unwrap())
     }
 
-    async fn set_entry_point(&mut self, entry_point: EntryPoint<V>) {
+    async fn set_entry_point(&mut self, entry_point: EntryPoint<V>) -> Result<()> {
         let op = Op::SetEntryPoint { entry_point };
         self.ops.send(op).await.unwrap();
+        Ok(())
     }
 
-    async fn get_links(&self, base: &V, lc: usize) -> FurthestQueue<V, D> {
+    async fn get_links(&self, base: &V, lc: usize) -> Result<FurthestQueue<V, D>> {
         let (reply, get_reply) = oneshot::channel();
 
         let op = Op::GetLinks {
@@ -181,12 +242,26 @@ impl<Q: Ref, V: Ref, D: Ref> GraphStore<OpsCollector<Q, V, D>> for OpsCollector<
         };
 
         self.ops.send(op).await.unwrap();
-        get_reply.await.unwrap()
+        Ok(get_reply.await.unwrap())
+    }
+
+    async fn get_links_batch(&self, bases: &[V], lc: usize) -> Result<Vec<FurthestQueue<V, D>>> {
+        let (reply, get_reply) = oneshot::channel();
+
+        let op = Op::GetLinksBatch {
+            bases: bases.to_vec(),
+            lc,
+            reply,
+        };
+
+        self.ops.send(op).await.unwrap();
+        Ok(get_reply.await.unwrap())
     }
 
-    async fn set_links(&mut self, base: V, links: FurthestQueue<V, D>, lc: usize) {
+    async fn set_links(&mut self, base: V, links: FurthestQueue<V, D>, lc: usize) -> Result<()> {
         let op = Op::SetLinks { base, links, lc };
         self.ops.send(op).await.unwrap();
+        Ok(())
     }
 }
 
@@ -196,6 +271,7 @@ mod tests {
 
     use super::Op::*;
     use super::*;
+    use crate::HawkError;
     use futures::stream::StreamExt;
 
     type Q = i64;
@@ -204,7 +280,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_search_to_insert_stream_empty() {
-        let mut stream = search_to_insert_stream::<Q, V, D>(0);
+        let mut stream = search_to_insert_stream::<Q, V, D>(0, None);
 
         let op = stream.next().await.unwrap();
         match op {
@@ -216,7 +292,7 @@ mod tests {
 
         let op = stream.next().await.unwrap();
         match op {
-            Op::SearchResult { query, result } => {
+            Op::SearchResult { query, result, .. } => {
                 assert_eq!(query, 0);
                 assert!(result.is_empty());
             }
@@ -234,7 +310,7 @@ mod tests {
             layer_count: 1,
         };
 
-        let mut stream = search_to_insert_stream::<Q, V, D>(some_query);
+        let mut stream = search_to_insert_stream::<Q, V, D>(some_query, None);
 
         let op = stream.next().await.unwrap();
         match op {
@@ -274,7 +350,7 @@ mod tests {
 
         let op = stream.next().await.unwrap();
         match op {
-            Op::SearchResult { query, result } => {
+            Op::SearchResult { query, result, .. } => {
                 assert_eq!(query, some_query);
                 assert_eq!(
                     result,
@@ -287,4 +363,378 @@ mod tests {
             _ => panic!("Expected SearchResult, got {:?}", op),
         }
     }
+
+    #[tokio::test]
+    async fn test_search_to_insert_stream_links_batch() {
+        let some_vec = 0;
+        let other_vec = 2;
+        let some_query = 1;
+        let some_distance = 10;
+        let other_distance = 20;
+        let ep = EntryPoint {
+            vector_ref: some_vec,
+            layer_count: 1,
+        };
+
+        let mut stream = search_to_insert_stream::<Q, V, D>(some_query, None);
+
+        let op = stream.next().await.unwrap();
+        match op {
+            GetEntryPoint { reply } => {
+                reply.send(Some(ep)).unwrap();
+            }
+            _ => panic!("Expected GetEntryPoint, got {:?}", op),
+        }
+
+        let op = stream.next().await.unwrap();
+        match op {
+            EvalDistanceBatch { reply, .. } => {
+                reply.send(vec![some_distance]).unwrap();
+            }
+            _ => panic!("Expected EvalDistanceBatch, got {:?}", op),
+        }
+
+        // The entry point is not further than itself, so it is visited.
+        let op = stream.next().await.unwrap();
+        match op {
+            LessThanBatch { reply, .. } => {
+                reply.send(vec![false]).unwrap();
+            }
+            _ => panic!("Expected LessThanBatch, got {:?}", op),
+        }
+
+        // The links of the batch of candidates are fetched in one operation.
+        let op = stream.next().await.unwrap();
+        match op {
+            GetLinksBatch { bases, lc, reply } => {
+                assert_eq!(bases, vec![some_vec]);
+                assert_eq!(lc, 0);
+                reply
+                    .send(vec![FurthestQueue::from_ascending_vec(vec![(
+                        other_vec,
+                        some_distance,
+                    )])])
+                    .unwrap();
+            }
+            _ => panic!("Expected GetLinksBatch, got {:?}", op),
+        }
+
+        let op = stream.next().await.unwrap();
+        match op {
+            EvalDistanceBatch {
+                query,
+                vectors,
+                reply,
+            } => {
+                assert_eq!(query, some_query);
+                assert_eq!(vectors, vec![other_vec]);
+                reply.send(vec![other_distance]).unwrap();
+            }
+            _ => panic!("Expected EvalDistanceBatch, got {:?}", op),
+        }
+
+        // Answer the remaining comparisons by value, until the result.
+        let result = loop {
+            let op = stream.next().await.unwrap();
+            match op {
+                LessThanBatch {
+                    distance,
+                    distances,
+                    reply,
+                } => {
+                    reply
+                        .send(distances.iter().map(|d| distance < *d).collect())
+                        .unwrap();
+                }
+                GetLinksBatch { bases, reply, .. } => {
+                    assert_eq!(bases, vec![other_vec]);
+                    reply.send(vec![FurthestQueue::new()]).unwrap();
+                }
+                EvalDistanceBatch { vectors, reply, .. } => {
+                    // No new neighbors.
+                    assert!(vectors.is_empty());
+                    reply.send(vec![]).unwrap();
+                }
+                SearchResult { result, .. } => break result,
+                _ => panic!("Unexpected {:?}", op),
+            }
+        };
+        assert_eq!(
+            result,
+            vec![FurthestQueue::from_ascending_vec(vec![
+                (some_vec, some_distance),
+                (other_vec, other_distance)
+            ])]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_stream_k_nearest() {
+        // A graph of 10 vectors on a line, all linked to each other in 2 layers. The distance is the difference.
+        let some_query = 3;
+        let all_vecs = (0..10).collect::<Vec<V>>();
+        let links = |base: V| {
+            FurthestQueue::from_ascending_vec(
+                all_vecs
+                    .iter()
+                    .filter(|v| **v != base)
+                    .map(|v| (*v, (v - base).abs()))
+                    .collect(),
+            )
+        };
+        let ep = EntryPoint {
+            vector_ref: 9,
+            layer_count: 2,
+        };
+
+        for k in [1, 4] {
+            let mut stream = search_to_insert_stream::<Q, V, D>(some_query, Some(k));
+            let result = loop {
+                let op = stream.next().await.unwrap();
+                match op {
+                    GetEntryPoint { reply } => reply.send(Some(ep.clone())).unwrap(),
+                    EvalDistanceBatch {
+                        query,
+                        vectors,
+                        reply,
+                    } => reply
+                        .send(vectors.iter().map(|v| (v - query).abs()).collect())
+                        .unwrap(),
+                    LessThanBatch {
+                        distance,
+                        distances,
+                        reply,
+                    } => reply
+                        .send(distances.iter().map(|d| distance < *d).collect())
+                        .unwrap(),
+                    GetLinks { base, reply, .. } => reply.send(links(base)).unwrap(),
+                    GetLinksBatch { bases, reply, .. } => reply
+                        .send(bases.iter().map(|base| links(*base)).collect())
+                        .unwrap(),
+                    SearchResult { query, result, .. } => {
+                        assert_eq!(query, some_query);
+                        break result;
+                    }
+                    _ => panic!("Unexpected {:?}", op),
+                }
+            };
+
+            // Only layer 0, with exactly the k nearest.
+            assert_eq!(result.len(), 1);
+            assert_eq!(result[0].len(), k);
+            assert_eq!(result[0].get_nearest(), Some(&(some_query, 0)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_to_insert_stream_with_visited() {
+        // A graph of 10 vectors on a line, with a vector linked to its 2 neighbors in layer 0, and to all vectors in
+        // layer 1. The distance is the difference.
+        let some_query = 3;
+        let all_vecs = (0..10).collect::<Vec<V>>();
+        let links = |base: V, lc: usize| {
+            FurthestQueue::from_ascending_vec(
+                all_vecs
+                    .iter()
+                    .filter(|v| **v != base && (lc == 1 || (*v - base).abs() == 1))
+                    .map(|v| (*v, (v - base).abs()))
+                    .collect(),
+            )
+        };
+        let ep = EntryPoint {
+            vector_ref: 9,
+            layer_count: 2,
+        };
+
+        let mut stream = search_to_insert_stream_with_visited::<Q, V, D>(some_query);
+        let (result, visited) = loop {
+            let op = stream.next().await.unwrap();
+            match op {
+                GetEntryPoint { reply } => reply.send(Some(ep.clone())).unwrap(),
+                EvalDistanceBatch {
+                    query,
+                    vectors,
+                    reply,
+                } => reply
+                    .send(vectors.iter().map(|v| (v - query).abs()).collect())
+                    .unwrap(),
+                LessThanBatch {
+                    distance,
+                    distances,
+                    reply,
+                } => reply
+                    .send(distances.iter().map(|d| distance < *d).collect())
+                    .unwrap(),
+                GetLinks { base, lc, reply } => reply.send(links(base, lc)).unwrap(),
+                GetLinksBatch { bases, lc, reply } => reply
+                    .send(bases.iter().map(|base| links(*base, lc)).collect())
+                    .unwrap(),
+                SearchResult {
+                    query,
+                    result,
+                    visited,
+                } => {
+                    assert_eq!(query, some_query);
+                    break (result, visited.unwrap());
+                }
+                _ => panic!("Unexpected {:?}", op),
+            }
+        };
+
+        // One visited set per layer, containing the neighbors found in that layer.
+        assert_eq!(result.len(), 2);
+        assert_eq!(visited.len(), 2);
+        for (neighbors, visited) in result.iter().zip(&visited) {
+            assert!(!neighbors.is_empty());
+            for (v, _) in neighbors.iter() {
+                assert!(visited.contains(v));
+            }
+        }
+        assert_eq!(result[0].get_nearest(), Some(&(some_query, 0)));
+        // Layer 1 is fully connected, so everything is visited there.
+        assert_eq!(visited[1].len(), all_vecs.len());
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_graph_ops() {
+        let (tx, _rx) = mpsc::channel(1);
+        let collector = OpsCollector::<Q, V, D> { ops: tx };
+        assert!(matches!(
+            collector.link_count(0).await,
+            Err(HawkError::Unsupported(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_trim_tie_break() {
+        // Answer the comparisons by value, with empty links for the neighbors.
+        let (tx, mut rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            while let Some(op) = rx.recv().await {
+                match op {
+                    LessThanBatch {
+                        distance,
+                        distances,
+                        reply,
+                    } => reply
+                        .send(distances.iter().map(|d| distance < *d).collect())
+                        .unwrap(),
+                    GetLinksBatch { bases, reply, .. } => reply
+                        .send(bases.iter().map(|_| FurthestQueue::new()).collect())
+                        .unwrap(),
+                    _ => panic!("Unexpected {:?}", op),
+                }
+            }
+        });
+        let collector = OpsCollector::<Q, V, D> { ops: tx };
+        let params = crate::hnsw_db::Params {
+            M: 4,
+            ..Default::default()
+        };
+        let hawk = HawkSearcher::new_with_params(
+            collector.clone(),
+            collector,
+            params,
+            &mut rand::rngs::mock::StepRng::new(0, 1),
+        );
+
+        // One candidate at distance 1, and 12 candidates at the same distance 2, in two orders.
+        let inserted = 100;
+        let tied = [9, 4, 11, 6, 3, 12, 7, 5, 10, 8, 13, 2];
+        let mut plans = vec![];
+        for order in [tied.to_vec(), tied.iter().rev().cloned().collect()] {
+            let mut candidates = vec![(1, 1)];
+            candidates.extend(order.into_iter().map(|v| (v, 2)));
+            let neighbors = FurthestQueue::from_ascending_vec(candidates);
+            let plan = hawk
+                .plan_insert(inserted, vec![neighbors], 0)
+                .await
+                .unwrap();
+            plans.push(format!("{:?}", plan));
+        }
+
+        // The same neighbors are kept, by the default `tie_break` of `OpsCollector`.
+        assert_eq!(plans[0], plans[1]);
+        let expected = FurthestQueue::from_ascending_vec(vec![(1, 1), (2, 2), (3, 2), (4, 2)]);
+        assert!(plans[0].ends_with(&format!(
+            "SetLinks {{ base: {}, links: {:?}, lc: 0 }}]",
+            inserted, expected
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_commit_search_result() {
+        use crate::examples::lazy_memory_store::LazyMemoryStore;
+        use crate::graph_store::GraphMem;
+
+        let mut db = HawkSearcher::from_seed(LazyMemoryStore::new(), GraphMem::new(), 0);
+        for raw_query in 0..100 {
+            let query = db.vector_store.prepare_query_u64(raw_query);
+            db.insert_unique(&query).await.unwrap();
+        }
+
+        // Answer the operations of the stream with the stores of the searcher.
+        let query = db.vector_store.prepare_query_u64(100);
+        let mut stream = search_to_insert_stream(query, None);
+        let result = loop {
+            let op = stream.next().await.unwrap();
+            match op {
+                GetEntryPoint { reply } => reply
+                    .send(db.graph_store.get_entry_point().await.unwrap())
+                    .unwrap(),
+                EvalDistanceBatch {
+                    query,
+                    vectors,
+                    reply,
+                } => reply
+                    .send(db.vector_store.eval_distance_batch(&query, &vectors).await)
+                    .unwrap(),
+                LessThanBatch {
+                    distance,
+                    distances,
+                    reply,
+                } => reply
+                    .send(db.vector_store.less_than_batch(&distance, &distances).await)
+                    .unwrap(),
+                GetLinks { base, lc, reply } => reply
+                    .send(db.graph_store.get_links(&base, lc).await.unwrap())
+                    .unwrap(),
+                GetLinksBatch { bases, lc, reply } => reply
+                    .send(db.graph_store.get_links_batch(&bases, lc).await.unwrap())
+                    .unwrap(),
+                SearchResult { result, .. } => break result,
+                _ => panic!("Unexpected {:?}", op),
+            }
+        };
+        assert_eq!(result, db.search_to_insert(&query).await.unwrap());
+
+        // A result without all the layers of the graph is rejected.
+        let inserted = db.vector_store.insert(&query).await;
+        let truncated = result[..result.len() - 1].to_vec();
+        assert!(matches!(
+            db.commit_search_result(inserted, truncated).await,
+            Err(crate::HawkError::GraphInconsistent(_))
+        ));
+        assert!(db
+            .graph_store
+            .get_links(&inserted, 0)
+            .await
+            .unwrap()
+            .is_empty());
+
+        db.commit_search_result(inserted, result).await.unwrap();
+        let nearest = db.search(&query, 1).await.unwrap();
+        assert_eq!(nearest[0].0, inserted);
+
+        // A result with empty layers is accepted, e.g. if the vectors found there are deleted.
+        let query = db.vector_store.prepare_query_u64(101);
+        let mut result = db.search_to_insert(&query).await.unwrap();
+        for layer in result.iter_mut().skip(1) {
+            *layer = FurthestQueue::new();
+        }
+        let inserted = db.vector_store.insert(&query).await;
+        db.commit_search_result(inserted, result).await.unwrap();
+        let nearest = db.search(&query, 1).await.unwrap();
+        assert_eq!(nearest[0].0, inserted);
+    }
 }
@@ -1,8 +1,12 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::hash::Hash;
 use std::ops::Deref;
 
-use serde::{Deserialize, Serialize};
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::VectorStore;
+use crate::{error::Result, VectorStore};
 
 pub type FurthestQueueV<V> =
     FurthestQueue<<V as VectorStore>::VectorRef, <V as VectorStore>::DistanceRef>;
@@ -10,6 +14,9 @@ pub type NearestQueueV<V> =
     NearestQueue<<V as VectorStore>::VectorRef, <V as VectorStore>::DistanceRef>;
 
 /// FurthestQueue is a list sorted in ascending order, with fast pop of the furthest element.
+///
+/// Insertion shifts the tail of the list. This is a small part of the construction time, see the benchmark
+/// `hnsw-furthest-queue`, and keeps the list contiguous for `Deref` to a slice.
 #[derive(Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FurthestQueue<Vector, Distance> {
     queue: Vec<(Vector, Distance)>,
@@ -24,14 +31,42 @@ impl<Vector: Clone, Distance: Clone> FurthestQueue<Vector, Distance> {
         FurthestQueue { queue }
     }
 
+    /// Build a queue from elements in any order, sorted with one call to `VectorStore::argsort`.
+    ///
+    /// Elements with equal distances keep their order in `elements`.
+    pub async fn from_unsorted<V>(store: &V, elements: Vec<(Vector, Distance)>) -> Self
+    where
+        V: VectorStore<VectorRef = Vector, DistanceRef = Distance>,
+    {
+        let distances = elements
+            .iter()
+            .map(|(_, dist)| dist.clone())
+            .collect::<Vec<_>>();
+        let order = store.argsort(&distances).await;
+
+        let mut elements = elements.into_iter().map(Some).collect::<Vec<_>>();
+        let queue = order
+            .into_iter()
+            .map(|i| {
+                elements[i]
+                    .take()
+                    .expect("argsort must return a permutation")
+            })
+            .collect::<Vec<_>>();
+
+        FurthestQueue { queue }
+    }
+
     /// Insert the element `to` with distance `dist` into the queue, maitaining the ascending order.
     ///
-    /// Call the VectorStore to come up with the insertion index.
+    /// Call the VectorStore to come up with the insertion index. Among equal distances, `to` is placed by
+    /// `VectorStore::tie_break`, so the order of the queue does not depend on the order of insertion. This takes one
+    /// more `less_than` if the next element goes before `to` by `tie_break`, and one per element at the same distance.
     pub async fn insert<V>(&mut self, store: &V, to: Vector, dist: Distance)
     where
         V: VectorStore<VectorRef = Vector, DistanceRef = Distance>,
     {
-        let index_asc = store
+        let mut index_asc = store
             .search_sorted(
                 &self
                     .queue
@@ -41,6 +76,15 @@ impl<Vector: Clone, Distance: Clone> FurthestQueue<Vector, Distance> {
                 &dist,
             )
             .await;
+
+        // The next elements are not nearer than `to`. Skip those at the same distance that go before it.
+        while let Some((v, d)) = self.queue.get(index_asc) {
+            if store.tie_break(v, &to) != Ordering::Less || store.less_than(&dist, d).await {
+                break;
+            }
+            index_asc += 1;
+        }
+
         self.queue.insert(index_asc, (to, dist));
     }
 
@@ -63,6 +107,103 @@ impl<Vector: Clone, Distance: Clone> FurthestQueue<Vector, Distance> {
     pub fn trim_to_k_nearest(&mut self, k: usize) {
         self.queue.truncate(k);
     }
+
+    /// Order the elements at the same distance as the k-th nearest by `VectorStore::tie_break`, so that which of them
+    /// are among the k nearest does not depend on the order of insertion.
+    ///
+    /// `insert` already keeps them in that order. This is for queues built otherwise, e.g. by `from_ascending_vec`.
+    ///
+    /// This takes one `less_than` per element of that run of equal distances, plus two to find its ends.
+    pub async fn break_ties_at<V>(&mut self, store: &V, k: usize)
+    where
+        V: VectorStore<VectorRef = Vector, DistanceRef = Distance>,
+    {
+        if k == 0 || k >= self.queue.len() {
+            return;
+        }
+        let boundary = self.queue[k - 1].1.clone();
+
+        let mut start = k - 1;
+        while start > 0 && !store.less_than(&self.queue[start - 1].1, &boundary).await {
+            start -= 1;
+        }
+        let mut end = k;
+        while end < self.queue.len() && !store.less_than(&boundary, &self.queue[end].1).await {
+            end += 1;
+        }
+
+        self.queue[start..end].sort_by(|(v1, _), (v2, _)| store.tie_break(v1, v2));
+    }
+
+    /// Merge the ascending queue `other` into this one, and keep the `k` nearest elements.
+    ///
+    /// This is a single sorted merge of both queues, with one `less_than` per element taken. Equal distances are taken
+    /// from this queue first. A vector present in both queues is kept once.
+    pub async fn merge<V>(&mut self, store: &V, other: FurthestQueue<Vector, Distance>, k: usize)
+    where
+        V: VectorStore<VectorRef = Vector, DistanceRef = Distance>,
+        Vector: Eq + Hash,
+    {
+        let mut merged = Vec::with_capacity(k.min(self.queue.len() + other.queue.len()));
+        let mut seen = HashSet::new();
+        let mut left = std::mem::take(&mut self.queue).into_iter().peekable();
+        let mut right = other.queue.into_iter().peekable();
+
+        while merged.len() < k {
+            let take_left = match (left.peek(), right.peek()) {
+                (Some((_, ld)), Some((_, rd))) => !store.less_than(rd, ld).await,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            let next = if take_left { left.next() } else { right.next() }.unwrap();
+            if seen.insert(next.0.clone()) {
+                merged.push(next);
+            }
+        }
+
+        self.queue = merged;
+    }
+
+    /// Whether the elements are in ascending order of distance, as compared by `VectorStore::less_than`.
+    ///
+    /// This holds for any queue built with the methods of `FurthestQueue`, but not necessarily for one built by
+    /// `from_ascending_vec` or decoded from an external source.
+    pub async fn is_ascending<V>(&self, store: &V) -> bool
+    where
+        V: VectorStore<VectorRef = Vector, DistanceRef = Distance>,
+    {
+        for pair in self.queue.windows(2) {
+            if store.less_than(&pair[1].1, &pair[0].1).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Keep only the elements for which `f` returns true, maintaining the order.
+    pub fn retain(&mut self, f: impl FnMut(&(Vector, Distance)) -> bool) {
+        self.queue.retain(f);
+    }
+}
+
+// Binary wire format.
+
+impl<Vector: Serialize + DeserializeOwned, Distance: Serialize + DeserializeOwned>
+    FurthestQueue<Vector, Distance>
+{
+    /// Encode the queue compactly with bincode: the number of elements, then the elements in ascending order.
+    ///
+    /// The integers are variable-length, so small IDs and distances take one byte. This is much smaller than JSON, to
+    /// send queues over a network.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::DefaultOptions::new().serialize(self)?)
+    }
+
+    /// Decode a queue encoded by `to_bytes`. Trailing bytes are an error.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(bincode::DefaultOptions::new().deserialize(bytes)?)
+    }
 }
 
 // Utility implementations.
@@ -102,6 +243,15 @@ impl<Vector: Clone, Distance: Clone> NearestQueue<Vector, Distance> {
         }
     }
 
+    pub fn from_ascending_vec(mut queue: Vec<(Vector, Distance)>) -> Self {
+        queue.reverse();
+        NearestQueue { queue }
+    }
+
+    pub fn from_descending_vec(queue: Vec<(Vector, Distance)>) -> Self {
+        NearestQueue { queue }
+    }
+
     /// Insert the element `to` with distance `dist` into the queue, maitaining the descending order.
     ///
     /// Call the VectorStore to come up with the insertion index.
@@ -124,8 +274,7 @@ impl<Vector: Clone, Distance: Clone> NearestQueue<Vector, Distance> {
         self.queue.insert(index_des, (to, dist));
     }
 
-    #[allow(dead_code)]
-    fn get_nearest(&self) -> Option<&(Vector, Distance)> {
+    pub fn get_nearest(&self) -> Option<&(Vector, Distance)> {
         self.queue.last()
     }
 
@@ -156,11 +305,12 @@ impl<Vector: Clone, Distance: Clone> Clone for NearestQueue<Vector, Distance> {
 mod tests {
     use super::*;
     use crate::examples::lazy_memory_store::LazyMemoryStore;
+    use crate::instrumented_store::InstrumentedStore;
 
     #[tokio::test]
     async fn test_furthest_queue() {
         let mut store = LazyMemoryStore::new();
-        let query = store.prepare_query(1);
+        let query = store.prepare_query_u64(1);
         let vector = store.insert(&query).await;
         let distance = store.eval_distance(&query, &vector).await;
 
@@ -177,4 +327,189 @@ mod tests {
         println!("{:?}", nearest_queue.get_nearest());
         println!("{:?}", nearest_queue.pop_nearest());
     }
+
+    #[tokio::test]
+    async fn test_queue_conversions() {
+        let mut store = LazyMemoryStore::new();
+        let query = store.prepare_query_u64(0);
+        let mut furthest_queue = FurthestQueue::new();
+        for raw_query in [0b1, 0b111, 0b11, 0b1111] {
+            let q = store.prepare_query_u64(raw_query);
+            let v = store.insert(&q).await;
+            let d = store.eval_distance(&query, &v).await;
+            furthest_queue.insert(&store, v, d).await;
+        }
+
+        let ascending: Vec<_> = furthest_queue.clone().into();
+        let nearest_queue = NearestQueue::from_ascending_vec(ascending);
+        assert_eq!(
+            nearest_queue,
+            NearestQueue::from_furthest_queue(&furthest_queue)
+        );
+
+        let descending = nearest_queue.to_vec();
+        assert_eq!(
+            NearestQueue::from_descending_vec(descending.clone()),
+            nearest_queue
+        );
+
+        // Back to a FurthestQueue.
+        let round_trip = FurthestQueue::from_ascending_vec(descending.into_iter().rev().collect());
+        assert_eq!(round_trip, furthest_queue);
+        assert_eq!(nearest_queue.get_nearest(), furthest_queue.get_nearest());
+    }
+
+    #[tokio::test]
+    async fn test_tie_break() {
+        let mut store = LazyMemoryStore::new();
+        let query = store.prepare_query_u64(0b000);
+        let near = store.prepare_query_u64(0b001);
+        let near = store.insert(&near).await;
+        // Two candidates at the same distance 2.
+        let tied = [0b011, 0b110];
+        let mut tied_ids = vec![];
+        for raw in tied {
+            let q = store.prepare_query_u64(raw);
+            tied_ids.push(store.insert(&q).await);
+        }
+
+        let mut kept = vec![];
+        for order in [[0, 1], [1, 0]] {
+            let mut queue = FurthestQueue::new();
+            let d = store.eval_distance(&query, &near).await;
+            queue.insert(&store, near, d).await;
+            for i in order {
+                let d = store.eval_distance(&query, &tied_ids[i]).await;
+                queue.insert(&store, tied_ids[i], d).await;
+            }
+
+            // `insert` already ordered the tied candidates.
+            let inserted = queue.clone();
+            queue.break_ties_at(&store, 2).await;
+            assert_eq!(queue, inserted);
+
+            // Keep one of the tied candidates.
+            assert!(queue.is_ascending(&store).await);
+            queue.trim_to_k_nearest(2);
+            assert_eq!(queue[0].0, near);
+            kept.push(queue[1].0);
+        }
+        assert_eq!(kept[0], kept[1]);
+        assert_eq!(kept[0], *tied_ids.iter().min().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_from_unsorted() {
+        let mut store = LazyMemoryStore::new();
+        let query = store.prepare_query_u64(0);
+        let mut elements = vec![];
+        // Small codes, for many equal distances.
+        for raw_vector in [5_u64, 3, 12, 0, 9, 6, 1, 10, 3, 7, 2, 8, 4, 11] {
+            let q = store.prepare_query_u64(raw_vector);
+            let v = store.insert(&q).await;
+            elements.push((v, store.eval_distance(&query, &v).await));
+        }
+
+        // A stable sort by distance.
+        let mut expected = elements.clone();
+        expected.sort_by_key(|(_, d)| store.actually_evaluate_distance(d));
+        let expected = FurthestQueue::from_ascending_vec(expected);
+        assert!(expected.is_ascending(&store).await);
+
+        let queue = FurthestQueue::from_unsorted(&store, elements.clone()).await;
+        assert_eq!(queue, expected);
+
+        // The same with the default argsort.
+        let instrumented = InstrumentedStore::new(store);
+        let queue = FurthestQueue::from_unsorted(&instrumented, elements).await;
+        assert_eq!(queue, expected);
+
+        let empty = FurthestQueue::from_unsorted(&instrumented, vec![]).await;
+        assert!(empty.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        let mut store = LazyMemoryStore::new();
+        let query = store.prepare_query_u64(0);
+        let mut vectors = vec![];
+        for raw_query in 1..9 {
+            let q = store.prepare_query_u64(raw_query);
+            vectors.push(store.insert(&q).await);
+        }
+
+        async fn queue_of(
+            store: &LazyMemoryStore,
+            query: &<LazyMemoryStore as VectorStore>::QueryRef,
+            vectors: &[<LazyMemoryStore as VectorStore>::VectorRef],
+        ) -> FurthestQueueV<LazyMemoryStore> {
+            let mut queue = FurthestQueue::new();
+            for v in vectors {
+                let d = store.eval_distance(query, v).await;
+                queue.insert(store, *v, d).await;
+            }
+            queue
+        }
+
+        let distances = |queue: &FurthestQueueV<LazyMemoryStore>| {
+            queue
+                .iter()
+                .map(|(_, d)| store.actually_evaluate_distance(d))
+                .collect::<Vec<_>>()
+        };
+
+        // Disjoint queues give the same distances as inserting everything. Equal distances may be in another order.
+        let mut merged = queue_of(&store, &query, &vectors[..4]).await;
+        let other = queue_of(&store, &query, &vectors[4..]).await;
+        merged.merge(&store, other, 5).await;
+        let mut expected = queue_of(&store, &query, &vectors).await;
+        expected.trim_to_k_nearest(5);
+        assert_eq!(distances(&merged), distances(&expected));
+        assert!(merged.is_ascending(&store).await);
+
+        // Overlapping vectors are kept once.
+        let mut merged = queue_of(&store, &query, &vectors[..6]).await;
+        let other = queue_of(&store, &query, &vectors[2..]).await;
+        merged.merge(&store, other, 100).await;
+        let expected = queue_of(&store, &query, &vectors).await;
+        assert_eq!(distances(&merged), distances(&expected));
+        let mut ids = merged.iter().map(|(v, _)| *v).collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vectors);
+    }
+
+    #[tokio::test]
+    async fn test_bytes() {
+        let mut store = LazyMemoryStore::new();
+        let query = store.prepare_query_u64(0);
+        let mut queue = FurthestQueue::new();
+        for raw_query in 1..33 {
+            let q = store.prepare_query_u64(raw_query);
+            let v = store.insert(&q).await;
+            let d = store.eval_distance(&query, &v).await;
+            queue.insert(&store, v, d).await;
+        }
+        assert_eq!(queue.len(), 32);
+        assert!(queue.is_ascending(&store).await);
+
+        let bytes = queue.to_bytes().unwrap();
+        assert_eq!(
+            FurthestQueueV::<LazyMemoryStore>::from_bytes(&bytes).unwrap(),
+            queue
+        );
+
+        let json = serde_json::to_vec(&queue).unwrap();
+        assert!(
+            bytes.len() * 2 < json.len(),
+            "{} vs {}",
+            bytes.len(),
+            json.len()
+        );
+
+        // Truncated or trailing bytes are an error.
+        assert!(FurthestQueueV::<LazyMemoryStore>::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(FurthestQueueV::<LazyMemoryStore>::from_bytes(&trailing).is_err());
+    }
 }
@@ -0,0 +1,275 @@
+use crate::{
+    error::Result, graph_store::EntryPoint, hnsw_db::FurthestQueueV, GraphStore, VectorStore,
+};
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use tokio::sync::RwLock;
+
+/// A store borrowed behind a lock, so that concurrent searches and a writer can use it, see
+/// `HawkSearcher::build_from_stream`.
+///
+/// Each operation holds the lock only while it runs: the reads share it, and the writes take it exclusively. So a
+/// write waits for the reads in progress, but not for the end of the searches that made them.
+///
+/// `VectorStore::tie_break` is not async, so it cannot wait for the lock. It is called on a copy of the vector store
+/// taken before the store was locked, which gives the same order since `tie_break` does not depend on the state of the
+/// store.
+pub(super) struct Shared<'a, 'b, T> {
+    lock: &'a RwLock<&'b mut T>,
+    copy: Option<&'a T>,
+}
+
+impl<'a, 'b, T> Shared<'a, 'b, T> {
+    pub fn new(lock: &'a RwLock<&'b mut T>) -> Self {
+        Shared { lock, copy: None }
+    }
+
+    /// Share a vector store, with a copy of it for `tie_break`.
+    pub fn with_copy(lock: &'a RwLock<&'b mut T>, copy: &'a T) -> Self {
+        Shared {
+            lock,
+            copy: Some(copy),
+        }
+    }
+}
+
+impl<T> Clone for Shared<'_, '_, T> {
+    fn clone(&self) -> Self {
+        Shared {
+            lock: self.lock,
+            copy: self.copy,
+        }
+    }
+}
+
+impl<T: Debug> Debug for Shared<'_, '_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Shared").field(self.lock).finish()
+    }
+}
+
+impl<V: VectorStore> VectorStore for Shared<'_, '_, V> {
+    type QueryRef = V::QueryRef;
+    type VectorRef = V::VectorRef;
+    type DistanceRef = V::DistanceRef;
+
+    const CHECK_SORTED: bool = V::CHECK_SORTED;
+
+    async fn insert(&mut self, query: &Self::QueryRef) -> Self::VectorRef {
+        self.lock.write().await.insert(query).await
+    }
+
+    async fn eval_distance(
+        &self,
+        query: &Self::QueryRef,
+        vector: &Self::VectorRef,
+    ) -> Self::DistanceRef {
+        self.lock.read().await.eval_distance(query, vector).await
+    }
+
+    async fn eval_distance_rev(
+        &self,
+        vector: &Self::VectorRef,
+        inserted: &Self::VectorRef,
+        distance: &Self::DistanceRef,
+    ) -> Self::DistanceRef {
+        self.lock
+            .read()
+            .await
+            .eval_distance_rev(vector, inserted, distance)
+            .await
+    }
+
+    async fn is_match(&self, distance: &Self::DistanceRef) -> bool {
+        self.lock.read().await.is_match(distance).await
+    }
+
+    async fn less_than(
+        &self,
+        distance1: &Self::DistanceRef,
+        distance2: &Self::DistanceRef,
+    ) -> bool {
+        self.lock.read().await.less_than(distance1, distance2).await
+    }
+
+    fn tie_break(&self, vector1: &Self::VectorRef, vector2: &Self::VectorRef) -> Ordering {
+        self.copy
+            .expect("a shared vector store has a copy for tie_break")
+            .tie_break(vector1, vector2)
+    }
+
+    async fn search_sorted(
+        &self,
+        distances: &[Self::DistanceRef],
+        target: &Self::DistanceRef,
+    ) -> usize {
+        self.lock
+            .read()
+            .await
+            .search_sorted(distances, target)
+            .await
+    }
+
+    async fn argsort(&self, distances: &[Self::DistanceRef]) -> Vec<usize> {
+        self.lock.read().await.argsort(distances).await
+    }
+
+    async fn insert_batch(&mut self, queries: &[Self::QueryRef]) -> Vec<Self::VectorRef> {
+        self.lock.write().await.insert_batch(queries).await
+    }
+
+    async fn eval_distance_batch(
+        &self,
+        query: &Self::QueryRef,
+        vectors: &[Self::VectorRef],
+    ) -> Vec<Self::DistanceRef> {
+        self.lock
+            .read()
+            .await
+            .eval_distance_batch(query, vectors)
+            .await
+    }
+
+    async fn eval_distance_cross(
+        &self,
+        queries: &[Self::QueryRef],
+        vectors: &[Self::VectorRef],
+    ) -> Vec<Vec<Self::DistanceRef>> {
+        self.lock
+            .read()
+            .await
+            .eval_distance_cross(queries, vectors)
+            .await
+    }
+
+    async fn less_than_batch(
+        &self,
+        distance: &Self::DistanceRef,
+        distances: &[Self::DistanceRef],
+    ) -> Vec<bool> {
+        self.lock
+            .read()
+            .await
+            .less_than_batch(distance, distances)
+            .await
+    }
+
+    async fn is_match_batch(&self, distances: &[Self::DistanceRef]) -> Vec<bool> {
+        self.lock.read().await.is_match_batch(distances).await
+    }
+}
+
+impl<'a, 'b, 'c, 'd, V: VectorStore, G: GraphStore<V>> GraphStore<Shared<'a, 'b, V>>
+    for Shared<'c, 'd, G>
+{
+    async fn get_entry_point(&self) -> Result<Option<EntryPoint<V::VectorRef>>> {
+        self.lock.read().await.get_entry_point().await
+    }
+
+    async fn set_entry_point(&mut self, entry_point: EntryPoint<V::VectorRef>) -> Result<()> {
+        self.lock.write().await.set_entry_point(entry_point).await
+    }
+
+    async fn get_links(&self, base: &V::VectorRef, lc: usize) -> Result<FurthestQueueV<V>> {
+        self.lock.read().await.get_links(base, lc).await
+    }
+
+    async fn get_links_batch(
+        &self,
+        bases: &[V::VectorRef],
+        lc: usize,
+    ) -> Result<Vec<FurthestQueueV<V>>> {
+        self.lock.read().await.get_links_batch(bases, lc).await
+    }
+
+    async fn with_links<R>(
+        &self,
+        base: &V::VectorRef,
+        lc: usize,
+        f: impl FnOnce(&FurthestQueueV<V>) -> R,
+    ) -> Result<R> {
+        self.lock.read().await.with_links(base, lc, f).await
+    }
+
+    async fn with_links_batch<R>(
+        &self,
+        bases: &[V::VectorRef],
+        lc: usize,
+        f: impl FnMut(&FurthestQueueV<V>) -> R,
+    ) -> Result<Vec<R>> {
+        self.lock.read().await.with_links_batch(bases, lc, f).await
+    }
+
+    async fn link_count(&self, lc: usize) -> Result<usize> {
+        self.lock.read().await.link_count(lc).await
+    }
+
+    async fn node_degree(&self, base: &V::VectorRef) -> Result<Vec<usize>> {
+        self.lock.read().await.node_degree(base).await
+    }
+
+    async fn get_all_links(&self, base: &V::VectorRef) -> Result<Vec<FurthestQueueV<V>>> {
+        self.lock.read().await.get_all_links(base).await
+    }
+
+    async fn get_inbound(&self, target: &V::VectorRef, lc: usize) -> Result<Vec<V::VectorRef>> {
+        self.lock.read().await.get_inbound(target, lc).await
+    }
+
+    async fn set_links(
+        &mut self,
+        base: V::VectorRef,
+        links: FurthestQueueV<V>,
+        lc: usize,
+    ) -> Result<()> {
+        self.lock.write().await.set_links(base, links, lc).await
+    }
+
+    async fn set_links_batch(
+        &mut self,
+        links: Vec<(V::VectorRef, FurthestQueueV<V>)>,
+        lc: usize,
+    ) -> Result<()> {
+        self.lock.write().await.set_links_batch(links, lc).await
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        self.lock.write().await.clear().await
+    }
+
+    async fn begin(&mut self) -> Result<()> {
+        self.lock.write().await.begin().await
+    }
+
+    async fn commit(&mut self) -> Result<()> {
+        self.lock.write().await.commit().await
+    }
+
+    async fn rollback(&mut self) -> Result<()> {
+        self.lock.write().await.rollback().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::examples::lazy_memory_store::LazyMemoryStore;
+
+    #[tokio::test]
+    async fn test_tie_break_while_locked() {
+        let mut store = LazyMemoryStore::new();
+        let first = store.prepare_query_u64(1);
+        let first = store.insert(&first).await;
+        let second = store.prepare_query_u64(2);
+        let second = store.insert(&second).await;
+
+        let copy = store.clone();
+        let lock = RwLock::new(&mut store);
+        let shared = Shared::with_copy(&lock, &copy);
+
+        // Ties are broken the same way while a writer holds the lock.
+        let _writer = lock.write().await;
+        assert_eq!(shared.tie_break(&first, &second), Ordering::Less);
+        assert_eq!(shared.tie_break(&second, &first), Ordering::Greater);
+    }
+}
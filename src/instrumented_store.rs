@@ -0,0 +1,237 @@
+use crate::{DataStore, UpdateStore, VectorStore};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A VectorStore wrapper that counts the operations, as a performance metric independent of the store.
+///
+/// Batch operations count one per element, and one call. `search_sorted` and `argsort` are not forwarded, so
+/// that their comparisons are counted as `less_than`. The counters are shared between clones.
+#[derive(Clone, Debug)]
+pub struct InstrumentedStore<V: VectorStore> {
+    inner: V,
+    counters: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    inserts: AtomicUsize,
+    evals: AtomicUsize,
+    less_thans: AtomicUsize,
+    is_matches: AtomicUsize,
+    calls: AtomicUsize,
+}
+
+impl<V: VectorStore> InstrumentedStore<V> {
+    pub fn new(inner: V) -> Self {
+        InstrumentedStore {
+            inner,
+            counters: Default::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &V {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut V {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> V {
+        self.inner
+    }
+
+    /// The number of vectors inserted.
+    pub fn insert_count(&self) -> usize {
+        self.counters.inserts.load(Ordering::Relaxed)
+    }
+
+    /// The number of distances evaluated, including the reverse distances.
+    pub fn eval_count(&self) -> usize {
+        self.counters.evals.load(Ordering::Relaxed)
+    }
+
+    /// The number of comparisons of distances.
+    pub fn less_than_count(&self) -> usize {
+        self.counters.less_thans.load(Ordering::Relaxed)
+    }
+
+    /// The number of match checks.
+    pub fn is_match_count(&self) -> usize {
+        self.counters.is_matches.load(Ordering::Relaxed)
+    }
+
+    /// The number of calls to the store, where a batch operation counts as one call.
+    ///
+    /// For a remote store, this is the number of round trips.
+    pub fn call_count(&self) -> usize {
+        self.counters.calls.load(Ordering::Relaxed)
+    }
+
+    /// Reset all the counters to zero.
+    pub fn reset_counts(&self) {
+        self.counters.inserts.store(0, Ordering::Relaxed);
+        self.counters.evals.store(0, Ordering::Relaxed);
+        self.counters.less_thans.store(0, Ordering::Relaxed);
+        self.counters.is_matches.store(0, Ordering::Relaxed);
+        self.counters.calls.store(0, Ordering::Relaxed);
+    }
+
+    fn count(&self, counter: &AtomicUsize, n: usize) {
+        counter.fetch_add(n, Ordering::Relaxed);
+        self.counters.calls.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<V: VectorStore> VectorStore for InstrumentedStore<V> {
+    type QueryRef = V::QueryRef;
+    type VectorRef = V::VectorRef;
+    type DistanceRef = V::DistanceRef;
+
+    const CHECK_SORTED: bool = V::CHECK_SORTED;
+
+    async fn insert(&mut self, query: &Self::QueryRef) -> Self::VectorRef {
+        self.count(&self.counters.inserts, 1);
+        self.inner.insert(query).await
+    }
+
+    async fn eval_distance(
+        &self,
+        query: &Self::QueryRef,
+        vector: &Self::VectorRef,
+    ) -> Self::DistanceRef {
+        self.count(&self.counters.evals, 1);
+        self.inner.eval_distance(query, vector).await
+    }
+
+    async fn eval_distance_rev(
+        &self,
+        vector: &Self::VectorRef,
+        inserted: &Self::VectorRef,
+        distance: &Self::DistanceRef,
+    ) -> Self::DistanceRef {
+        self.count(&self.counters.evals, 1);
+        self.inner
+            .eval_distance_rev(vector, inserted, distance)
+            .await
+    }
+
+    async fn is_match(&self, distance: &Self::DistanceRef) -> bool {
+        self.count(&self.counters.is_matches, 1);
+        self.inner.is_match(distance).await
+    }
+
+    async fn less_than(
+        &self,
+        distance1: &Self::DistanceRef,
+        distance2: &Self::DistanceRef,
+    ) -> bool {
+        self.count(&self.counters.less_thans, 1);
+        self.inner.less_than(distance1, distance2).await
+    }
+
+    fn tie_break(
+        &self,
+        vector1: &Self::VectorRef,
+        vector2: &Self::VectorRef,
+    ) -> std::cmp::Ordering {
+        self.inner.tie_break(vector1, vector2)
+    }
+
+    async fn insert_batch(&mut self, queries: &[Self::QueryRef]) -> Vec<Self::VectorRef> {
+        self.count(&self.counters.inserts, queries.len());
+        self.inner.insert_batch(queries).await
+    }
+
+    async fn eval_distance_batch(
+        &self,
+        query: &Self::QueryRef,
+        vectors: &[Self::VectorRef],
+    ) -> Vec<Self::DistanceRef> {
+        self.count(&self.counters.evals, vectors.len());
+        self.inner.eval_distance_batch(query, vectors).await
+    }
+
+    async fn less_than_batch(
+        &self,
+        distance: &Self::DistanceRef,
+        distances: &[Self::DistanceRef],
+    ) -> Vec<bool> {
+        self.count(&self.counters.less_thans, distances.len());
+        self.inner.less_than_batch(distance, distances).await
+    }
+
+    async fn is_match_batch(&self, distances: &[Self::DistanceRef]) -> Vec<bool> {
+        self.count(&self.counters.is_matches, distances.len());
+        self.inner.is_match_batch(distances).await
+    }
+}
+
+impl<V: DataStore> DataStore for InstrumentedStore<V> {
+    type Data = V::Data;
+
+    async fn len(&self) -> usize {
+        self.inner.len().await
+    }
+
+    async fn get_data(&self, vector: &Self::VectorRef) -> Option<Self::Data> {
+        self.inner.get_data(vector).await
+    }
+
+    fn query_from_vector(&self, vector: &Self::VectorRef) -> Self::QueryRef {
+        self.inner.query_from_vector(vector)
+    }
+}
+
+impl<V: UpdateStore> UpdateStore for InstrumentedStore<V> {
+    async fn update_data(&mut self, vector: &Self::VectorRef, query: &Self::QueryRef) {
+        self.inner.update_data(vector, query).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::examples::lazy_memory_store::LazyMemoryStore;
+    use crate::graph_store::GraphMem;
+    use crate::hnsw_db::HawkSearcher;
+    use crate::linear_db::LinearDb;
+
+    #[tokio::test]
+    async fn test_search_vs_linear() {
+        let database_size = 10_000;
+        let k = 10;
+
+        let mut linear = LinearDb::new(InstrumentedStore::new(LazyMemoryStore::new()));
+        let mut hnsw = HawkSearcher::from_seed(
+            InstrumentedStore::new(LazyMemoryStore::new()),
+            GraphMem::new(),
+            0,
+        );
+        for raw_query in 0..database_size {
+            let query = linear.store.inner_mut().prepare_query_u64(raw_query);
+            linear.insert(&query).await;
+
+            let query = hnsw.vector_store.inner_mut().prepare_query_u64(raw_query);
+            hnsw.insert_unique(&query).await.unwrap();
+        }
+        assert_eq!(linear.store.insert_count(), database_size as usize);
+        assert_eq!(hnsw.vector_store.insert_count(), database_size as usize);
+
+        linear.store.reset_counts();
+        hnsw.vector_store.reset_counts();
+        let query = linear.store.inner_mut().prepare_query_u64(database_size);
+        linear.knn(&query, k).await;
+        let query = hnsw
+            .vector_store
+            .inner_mut()
+            .prepare_query_u64(database_size);
+        hnsw.search(&query, k).await.unwrap();
+
+        let linear_evals = linear.store.eval_count();
+        let hnsw_evals = hnsw.vector_store.eval_count();
+        assert_eq!(linear_evals, database_size as usize);
+        assert!(hnsw_evals < linear_evals / 2, "hnsw: {}", hnsw_evals);
+        assert!(hnsw.vector_store.less_than_count() > 0);
+    }
+}
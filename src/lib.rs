@@ -1,12 +1,21 @@
 pub mod graph_store;
 pub mod hnsw_db;
 
+pub mod caching_store;
+pub mod error;
+pub mod eval;
 pub mod examples;
+pub mod instrumented_store;
 pub mod linear_db;
+pub mod metrics;
 
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::hash::Hash;
 
+use bincode::Options;
+pub use error::HawkError;
 pub use graph_store::GraphStore;
 use serde::Serialize;
 
@@ -38,6 +47,14 @@ pub trait VectorStore: Clone + Debug {
     /// Example: an encrypted distance.
     type DistanceRef: Ref;
 
+    /// Whether debug builds check that the distances given to `search_sorted`, and the links written by
+    /// `HawkSearcher::apply_plan`, are in ascending order.
+    ///
+    /// The check costs one `less_than` per adjacent pair, on every insertion into a queue. It is off by default: in a
+    /// secret-shared store, each comparison is a round trip and reveals the order of two distances, and a debug party
+    /// would make different operations than a release party. Enable it for stores where comparisons are cheap.
+    const CHECK_SORTED: bool = false;
+
     /// Persist a query as a new vector in the store, and return a reference to it.
     async fn insert(&mut self, query: &Self::QueryRef) -> Self::VectorRef;
 
@@ -48,6 +65,21 @@ pub trait VectorStore: Clone + Debug {
         vector: &Self::VectorRef,
     ) -> Self::DistanceRef;
 
+    /// Evaluate the distance from a stored vector to an inserted vector, given the distance in the other direction.
+    /// That is, `distance` was evaluated with the query of the inserted vector against the stored vector.
+    ///
+    /// This is used to create the back-links from the neighbors of a newly inserted vector.
+    /// The default implementation assumes a symmetric metric and returns `distance` unchanged.
+    /// Override for asymmetric metrics.
+    async fn eval_distance_rev(
+        &self,
+        _vector: &Self::VectorRef,
+        _inserted: &Self::VectorRef,
+        distance: &Self::DistanceRef,
+    ) -> Self::DistanceRef {
+        distance.clone()
+    }
+
     /// Check whether a distance is a match, meaning the query is considered equivalent to a previously inserted vector.
     async fn is_match(&self, distance: &Self::DistanceRef) -> bool;
 
@@ -55,12 +87,42 @@ pub trait VectorStore: Clone + Debug {
     async fn less_than(&self, distance1: &Self::DistanceRef, distance2: &Self::DistanceRef)
         -> bool;
 
+    /// Order two vectors at equal distances, to choose which ones are kept when trimming the links of a vector.
+    ///
+    /// This must be a total order that depends only on the references, not on the state of the store, so that all the
+    /// parties of a secret-shared store trim identically, whatever the order in which they found the candidates.
+    /// The default implementation compares the big-endian bincode encodings of the references, which orders unsigned
+    /// integer IDs by value. Override it for a cheaper order, e.g. by ID as in `LazyMemoryStore`.
+    fn tie_break(&self, vector1: &Self::VectorRef, vector2: &Self::VectorRef) -> Ordering {
+        let encode = |vector: &Self::VectorRef| {
+            bincode::DefaultOptions::new()
+                .with_big_endian()
+                .with_fixint_encoding()
+                .serialize(vector)
+                .expect("a VectorRef must be serializable")
+        };
+        encode(vector1).cmp(&encode(vector2))
+    }
+
     /// Find the insertion index for a target distance to maintain order in a list of ascending distances.
+    ///
+    /// `distances` must be sorted in ascending order, otherwise the result is meaningless.
+    /// In debug builds of stores with `CHECK_SORTED`, this is checked with one `less_than` per adjacent pair, and
+    /// panics if it does not hold.
     async fn search_sorted(
         &self,
         distances: &[Self::DistanceRef],
         target: &Self::DistanceRef,
     ) -> usize {
+        if cfg!(debug_assertions) && Self::CHECK_SORTED {
+            for pair in distances.windows(2) {
+                assert!(
+                    !self.less_than(&pair[1], &pair[0]).await,
+                    "search_sorted requires distances in ascending order"
+                );
+            }
+        }
+
         let mut left = 0;
         let mut right = distances.len();
 
@@ -75,6 +137,42 @@ pub trait VectorStore: Clone + Debug {
         left
     }
 
+    /// Sort a batch of distances, and return the indices of the distances in ascending order.
+    ///
+    /// Equal distances keep their order in `distances`.
+    /// The default implementation is a merge sort with `less_than`, about n*log2(n) comparisons.
+    /// Override for stores that can sort the distances in a single call.
+    async fn argsort(&self, distances: &[Self::DistanceRef]) -> Vec<usize> {
+        let mut indices = (0..distances.len()).collect::<Vec<_>>();
+        let mut width = 1;
+
+        while width < indices.len() {
+            let mut merged = Vec::with_capacity(indices.len());
+            for chunk in indices.chunks(2 * width) {
+                let (left, right) = chunk.split_at(width.min(chunk.len()));
+                let (mut i, mut j) = (0, 0);
+                while i < left.len() && j < right.len() {
+                    // Take from the right only if strictly less, to keep equal distances in order.
+                    if self
+                        .less_than(&distances[right[j]], &distances[left[i]])
+                        .await
+                    {
+                        merged.push(right[j]);
+                        j += 1;
+                    } else {
+                        merged.push(left[i]);
+                        i += 1;
+                    }
+                }
+                merged.extend_from_slice(&left[i..]);
+                merged.extend_from_slice(&right[j..]);
+            }
+            indices = merged;
+            width *= 2;
+        }
+        indices
+    }
+
     // Batch variants.
 
     /// Persist a batch of queries as new vectors in the store, and return references to them.
@@ -103,6 +201,22 @@ pub trait VectorStore: Clone + Debug {
         results
     }
 
+    /// Evaluate the distances between each of a batch of queries and each of a batch of vectors.
+    /// The result has one row per query, with one distance per vector.
+    /// The default implementation is a loop over `eval_distance_batch`.
+    /// Override for a more efficient cross product, e.g. to fetch each vector only once.
+    async fn eval_distance_cross(
+        &self,
+        queries: &[Self::QueryRef],
+        vectors: &[Self::VectorRef],
+    ) -> Vec<Vec<Self::DistanceRef>> {
+        let mut results = Vec::with_capacity(queries.len());
+        for query in queries {
+            results.push(self.eval_distance_batch(query, vectors).await);
+        }
+        results
+    }
+
     /// Compare a distance with a batch of distances.
     /// The default implementation is a loop over `less_than`.
     /// Override for more efficient batch comparisons.
@@ -117,4 +231,78 @@ pub trait VectorStore: Clone + Debug {
         }
         results
     }
+
+    /// Check whether each of a batch of distances is a match.
+    /// The default implementation is a loop over `is_match`.
+    /// Override for more efficient batch checks.
+    async fn is_match_batch(&self, distances: &[Self::DistanceRef]) -> Vec<bool> {
+        let mut results = Vec::with_capacity(distances.len());
+        for distance in distances {
+            results.push(self.is_match(distance).await);
+        }
+        results
+    }
+}
+
+/// A vector store that can read back its vectors.
+///
+/// This is used by `HawkSearcher::index_header`, `enable_recall_probe` and `compact`.
+#[allow(async_fn_in_trait)]
+pub trait DataStore: VectorStore {
+    /// The original data of a stored vector, as returned by `get_data`.
+    ///
+    /// Example: the raw code of a vector.
+    type Data;
+
+    /// The number of persisted vectors, not counting pending queries.
+    async fn len(&self) -> usize;
+
+    async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Read back the original data of a stored vector, or None if there is no such vector.
+    async fn get_data(&self, vector: &Self::VectorRef) -> Option<Self::Data>;
+
+    /// Make a query with the data of a persisted vector, to search with it, e.g. for the neighbors of the vector.
+    /// This is not async: the conversion is expected to be an identity or a lookup in memory.
+    ///
+    /// Example: the same ID if queries and vectors share their IDs, or a query prepared from the data of the vector.
+    fn query_from_vector(&self, vector: &Self::VectorRef) -> Self::QueryRef;
+}
+
+/// A vector store that can replace the data of its vectors.
+///
+/// This is used by `HawkSearcher::update`.
+#[allow(async_fn_in_trait)]
+pub trait UpdateStore: DataStore {
+    /// Replace the data of a persisted vector with the data of a query. The vector keeps its reference.
+    async fn update_data(&mut self, vector: &Self::VectorRef, query: &Self::QueryRef);
+}
+
+/// A vector store that can remove vectors, and give dense references to the others.
+///
+/// This is used by `HawkSearcher::compact_and_renumber`.
+#[allow(async_fn_in_trait)]
+pub trait RenumberStore: VectorStore {
+    /// Keep only the given persisted vectors, and remove the other vectors and the pending queries.
+    ///
+    /// The kept vectors get new dense references, in the order of the store. Return the new reference of each of them.
+    async fn renumber(
+        &mut self,
+        keep: &HashSet<Self::VectorRef>,
+    ) -> HashMap<Self::VectorRef, Self::VectorRef>;
+
+    /// Translate the distance of a link evaluated before `renumber`, given the new references of the base vector of the
+    /// link and of its neighbor.
+    ///
+    /// The default implementation returns the distance unchanged, for distances that do not refer to vectors.
+    fn renumber_distance(
+        &self,
+        distance: &Self::DistanceRef,
+        _base: &Self::VectorRef,
+        _neighbor: &Self::VectorRef,
+    ) -> Self::DistanceRef {
+        distance.clone()
+    }
 }
@@ -1,4 +1,4 @@
-use crate::VectorStore;
+use crate::{hnsw_db::FurthestQueueV, VectorStore};
 
 #[derive(Clone)]
 pub struct LinearDb<V: VectorStore> {
@@ -24,6 +24,31 @@ impl<V: VectorStore> LinearDb<V> {
         true
     }
 
+    /// Find the exact k nearest neighbors of a query by a brute-force scan, in ascending order of distance.
+    pub async fn knn(&self, query: &V::QueryRef, k: usize) -> Vec<(V::VectorRef, V::DistanceRef)> {
+        let distances = self.store.eval_distance_batch(query, &self.vectors).await;
+
+        let mut nearest = FurthestQueueV::<V>::new();
+        for (vector, distance) in self.vectors.iter().zip(distances) {
+            nearest.insert(&self.store, vector.clone(), distance).await;
+            nearest.trim_to_k_nearest(k);
+        }
+        nearest.into()
+    }
+
+    /// Find the exact k nearest neighbors of each query of a batch.
+    pub async fn knn_many(
+        &self,
+        queries: &[V::QueryRef],
+        k: usize,
+    ) -> Vec<Vec<(V::VectorRef, V::DistanceRef)>> {
+        let mut results = Vec::with_capacity(queries.len());
+        for query in queries {
+            results.push(self.knn(query, k).await);
+        }
+        results
+    }
+
     async fn exists(&mut self, query: &V::QueryRef) -> bool {
         for vector in &self.vectors {
             let distance = self.store.eval_distance(query, vector).await;
@@ -39,15 +64,53 @@ impl<V: VectorStore> LinearDb<V> {
 mod tests {
     use super::*;
     use crate::examples::lazy_memory_store::LazyMemoryStore;
+    use crate::graph_store::GraphMem;
+    use crate::hnsw_db::test_utils::{insert_all, prepare_queries};
+    use crate::hnsw_db::HawkSearcher;
+    use aes_prng::AesRng;
+    use rand::{Rng, SeedableRng};
 
     #[tokio::test]
     async fn test_linear_db() {
         let store = LazyMemoryStore::new();
         let mut db = LinearDb::new(store);
 
-        let query = db.store.prepare_query(123);
+        let query = db.store.prepare_query_u64(123);
 
         assert!(db.insert(&query).await);
         assert!(!db.insert(&query).await);
     }
+
+    #[tokio::test]
+    async fn test_knn_recall() {
+        let mut rng = AesRng::seed_from_u64(0_u64);
+        let mut store = LazyMemoryStore::new();
+        let queries = prepare_queries(&mut store, (0..500).map(|_| rng.gen()));
+        let test_queries = prepare_queries(&mut store, (0..20).map(|_| rng.gen()));
+
+        let mut linear = LinearDb::new(store.clone());
+        let mut hnsw = HawkSearcher::new(store, GraphMem::new(), &mut rng);
+        for query in queries.iter() {
+            linear.insert(query).await;
+        }
+        insert_all(&mut hnsw, &queries).await;
+
+        let k = 10;
+        let truth = linear.knn_many(&test_queries, k).await;
+
+        let mut found = 0;
+        for (query, truth) in test_queries.iter().zip(truth) {
+            assert_eq!(truth.len(), k);
+            let (_, kth_distance) = truth.last().unwrap();
+
+            // Count the results within the true k-th distance, since there are ties between Hamming distances.
+            for (_, distance) in hnsw.search(query, k).await.unwrap() {
+                if !hnsw.vector_store.less_than(kth_distance, &distance).await {
+                    found += 1;
+                }
+            }
+        }
+        let recall = found as f64 / (k * test_queries.len()) as f64;
+        assert!(recall >= 0.9, "recall@{}: {}", k, recall);
+    }
 }
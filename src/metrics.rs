@@ -0,0 +1,164 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+
+/// A float distance that can be used as a `DistanceRef`, for stores with float metrics such as the cosine distance.
+///
+/// `f32` is not `Eq` nor `Hash`, as required by `Ref`. This wrapper compares and hashes the values with a total order:
+/// -0.0 equals 0.0, and all the NaNs are equal to each other and greater than any number. So a store can implement
+/// `less_than` as `d1 < d2`, and a NaN distance, e.g. of a zero vector, is never nearer than a real distance.
+///
+/// In JSON, NaN and the infinities are written as the strings `"NaN"`, `"inf"` and `"-inf"`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OrderedDistance(pub f32);
+
+impl OrderedDistance {
+    /// The value with a single representation of zero and of NaN.
+    fn canonical(&self) -> f32 {
+        if self.0.is_nan() {
+            f32::NAN
+        } else if self.0 == 0.0 {
+            0.0
+        } else {
+            self.0
+        }
+    }
+}
+
+impl From<f32> for OrderedDistance {
+    fn from(distance: f32) -> Self {
+        OrderedDistance(distance)
+    }
+}
+
+impl PartialEq for OrderedDistance {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OrderedDistance {}
+
+impl PartialOrd for OrderedDistance {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedDistance {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.canonical().total_cmp(&other.canonical())
+    }
+}
+
+impl Hash for OrderedDistance {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.canonical().to_bits().hash(state);
+    }
+}
+
+impl Serialize for OrderedDistance {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() && !self.0.is_finite() {
+            // JSON has no NaN nor infinity.
+            serializer.collect_str(&self.0)
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for OrderedDistance {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Number(f32),
+            Text(String),
+        }
+
+        if deserializer.is_human_readable() {
+            match Repr::deserialize(deserializer)? {
+                Repr::Number(distance) => Ok(OrderedDistance(distance)),
+                Repr::Text(text) => text
+                    .parse()
+                    .map(OrderedDistance)
+                    .map_err(serde::de::Error::custom),
+            }
+        } else {
+            Ok(OrderedDistance(f32::deserialize(deserializer)?))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+
+    fn hash_of(d: OrderedDistance) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        d.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_ordering() {
+        let nan = OrderedDistance(f32::NAN);
+        let other_nan = OrderedDistance(f32::from_bits(f32::NAN.to_bits() ^ 1));
+        let neg_nan = OrderedDistance(-f32::NAN);
+        assert!(other_nan.0.is_nan() && neg_nan.0.is_nan());
+
+        let mut distances = [
+            nan,
+            OrderedDistance(1.0),
+            OrderedDistance(f32::INFINITY),
+            OrderedDistance(-0.0),
+            neg_nan,
+            OrderedDistance(0.5),
+            OrderedDistance(0.0),
+            other_nan,
+        ];
+        distances.sort();
+        let values = distances.iter().map(|d| d.0).collect::<Vec<_>>();
+        assert_eq!(values[..5], [0.0, 0.0, 0.5, 1.0, f32::INFINITY]);
+        assert!(values[5..].iter().all(|d| d.is_nan()));
+
+        // Zeros and NaNs are equal, with equal hashes.
+        for (a, b) in [
+            (OrderedDistance(0.0), OrderedDistance(-0.0)),
+            (nan, other_nan),
+            (nan, neg_nan),
+        ] {
+            assert_eq!(a, b);
+            assert_eq!(a.cmp(&b), Ordering::Equal);
+            assert_eq!(hash_of(a), hash_of(b));
+        }
+        assert_ne!(nan, OrderedDistance(f32::INFINITY));
+        assert!(OrderedDistance(f32::INFINITY) < nan);
+        assert!(OrderedDistance(-1.0) < OrderedDistance(-0.0));
+    }
+
+    #[test]
+    fn test_serialization() {
+        for d in [0.25, -0.0, f32::INFINITY, f32::NEG_INFINITY, f32::NAN] {
+            let d = OrderedDistance(d);
+
+            let json = serde_json::to_string(&d).unwrap();
+            assert_eq!(serde_json::from_str::<OrderedDistance>(&json).unwrap(), d);
+
+            let bytes = bincode::serialize(&d).unwrap();
+            assert_eq!(bytes.len(), 4);
+            assert_eq!(bincode::deserialize::<OrderedDistance>(&bytes).unwrap(), d);
+        }
+        assert_eq!(
+            serde_json::to_string(&OrderedDistance(f32::NAN)).unwrap(),
+            "\"NaN\""
+        );
+        assert_eq!(
+            serde_json::from_str::<OrderedDistance>("\"-inf\"").unwrap(),
+            OrderedDistance(f32::NEG_INFINITY)
+        );
+        assert!(serde_json::from_str::<OrderedDistance>("\"far\"").is_err());
+    }
+}